@@ -5,19 +5,23 @@ use std::fs::File;
 use std::io::{stderr, stdout, BufReader, Write};
 use std::path::Path;
 use std::process;
-use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
 
+use log::warn;
 use petgraph::graph::NodeIndex;
 use petgraph::{EdgeDirection, Graph};
 
 use octobuild::cluster::client::RemoteCompiler;
 use octobuild::compiler::{CommandArgs, Compiler, SharedState};
 use octobuild::config::Config;
+use octobuild::console::StatusLine;
+use octobuild::io::history::TaskHistory;
 use octobuild::simple::supported_compilers;
 use octobuild::version;
 use octobuild::worker::execute_graph;
 use octobuild::worker::validate_graph;
-use octobuild::worker::{BuildAction, BuildGraph, BuildResult, BuildTask};
+use octobuild::worker::{BuildAction, BuildGraph, BuildResult, BuildStatus, BuildTask};
 use octobuild::xg;
 use octobuild::xg::parser::{XgGraph, XgNode};
 
@@ -48,7 +52,7 @@ pub fn main() -> octobuild::Result<()> {
 
 fn execute(config: &Config, args: &[String]) -> octobuild::Result<()> {
     let state = SharedState::new(config)?;
-    let compiler = RemoteCompiler::new(&config.coordinator, supported_compilers());
+    let compiler = RemoteCompiler::new(config, supported_compilers())?;
 
     match args.first() {
         None => Err(octobuild::Error::NoTaskFiles),
@@ -68,8 +72,30 @@ fn execute(config: &Config, args: &[String]) -> octobuild::Result<()> {
                 xg::parser::parse(&mut graph, BufReader::new(file))?;
                 let build_graph = prepare_graph(&compiler, validate_graph(graph)?, config)?;
 
-                let result =
-                    execute_graph(&state, build_graph, config.process_limit, print_task_result);
+                let output = Mutex::new(TaskOutput {
+                    status: StatusLine::new(),
+                    grouped: config.grouped_output,
+                    failures: Vec::new(),
+                });
+                // Other tools run single compilations, so they don't need task history
+                let history = TaskHistory::new(config);
+                let result = execute_graph(
+                    &state,
+                    build_graph,
+                    config.process_limit,
+                    Some(&history),
+                    |result| print_task_result(&state, &output, result),
+                    |status| {
+                        let mut output = output.lock().unwrap();
+                        print_status(&state, &mut output.status, status)
+                    },
+                );
+                if let Err(e) = history.save() {
+                    warn!("Can't save task history: {e}");
+                }
+                let mut output = output.into_inner().unwrap();
+                output.status.clear(&mut stdout())?;
+                print_failures(&output.failures)?;
                 drop(state.cache.cleanup());
                 writeln!(stdout(), "{}", state.statistic)?;
                 result
@@ -146,17 +172,100 @@ fn prepare_graph<C: Compiler>(
     validate_graph(result)
 }
 
-fn print_task_result(result: &BuildResult) -> octobuild::Result<()> {
-    writeln!(
-        stdout(),
-        "#{} {}/{}: {} @ {}s",
+struct TaskOutput {
+    status: StatusLine,
+    // Print every task output as single block
+    grouped: bool,
+    // Title and output of failed tasks
+    failures: Vec<(String, Vec<u8>)>,
+}
+
+fn print_task_result(
+    state: &SharedState,
+    output: &Mutex<TaskOutput>,
+    result: &BuildResult,
+) -> octobuild::Result<()> {
+    let mut output = output.lock().unwrap();
+    let TaskOutput {
+        status,
+        grouped,
+        failures,
+    } = &mut *output;
+    status.clear(&mut stdout())?;
+    let eta = format_eta(&result.status);
+    let header = format!(
+        "#{} {}/{}: {} @ {}s{eta}",
         result.worker,
-        result.completed,
-        result.total,
+        result.status.completed,
+        result.status.total,
         result.task.title,
         result.result.duration.as_secs(),
+    );
+    if *grouped {
+        let mut block = Vec::new();
+        result.result.write_output(&mut block)?;
+        let mut out = stdout().lock();
+        writeln!(out, "{header}")?;
+        out.write_all(&block)?;
+        out.flush()?;
+        if !result.result.success() {
+            failures.push((result.task.title.clone(), block));
+        }
+    } else {
+        writeln!(stdout(), "{header}")?;
+        result.result.print_output()?;
+    }
+    print_status(state, status, &result.status)
+}
+
+fn format_eta(status: &BuildStatus) -> String {
+    status
+        .estimate
+        .map(|v| format!(", ETA {}s", v.as_secs()))
+        .unwrap_or_default()
+}
+
+fn print_status(
+    state: &SharedState,
+    status: &mut StatusLine,
+    build: &BuildStatus,
+) -> octobuild::Result<()> {
+    if !status.is_enabled() {
+        return Ok(());
+    }
+    let running: Vec<&str> = build
+        .running
+        .iter()
+        .map(|task| task.title.as_str())
+        .collect();
+    status.show(
+        &mut stdout(),
+        &format!(
+            "[{}/{}] cache hit {}%, remote {}{} | {}",
+            build.completed,
+            build.total,
+            state.statistic.hit_percent(),
+            state.statistic.remote_active.load(Ordering::Relaxed),
+            format_eta(build),
+            running.join(", "),
+        ),
     )?;
-    result.result.print_output()?;
+    Ok(())
+}
+
+fn print_failures(failures: &[(String, Vec<u8>)]) -> octobuild::Result<()> {
+    if failures.is_empty() {
+        return Ok(());
+    }
+    let mut out = stdout().lock();
+    writeln!(out)?;
+    writeln!(out, "Failed tasks ({}):", failures.len())?;
+    for (title, output) in failures {
+        writeln!(out, "==== {title} ====")?;
+        out.write_all(output)?;
+    }
+    writeln!(out)?;
+    out.flush()?;
     Ok(())
 }
 
@@ -1,87 +1,144 @@
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
 use std::ffi::OsString;
 use std::fs;
 use std::fs::File;
+use std::io::{Cursor, Read};
 use std::net::SocketAddr;
-use std::path::PathBuf;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::mpsc::Receiver;
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 use std::thread::JoinHandle;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
 use daemon::Daemon;
 use daemon::DaemonRunner;
 use daemon::State;
-use log::info;
+use log::{info, warn};
 use path_absolutize::Absolutize;
-use rouille::{router, try_or_400, Request, Response, Server};
+use rouille::{router, try_or_400, Request, Response, ResponseBody};
 use sha2::digest::DynDigest;
 use sha2::{Digest, Sha256};
 
-use octobuild::cluster::builder::{CompileRequest, CompileResponse};
+use octobuild::cluster::auth::{body_digest, ClusterAuth};
+use octobuild::cluster::builder::{
+    CompileRequest, CompileResponse, OutputFile, OutputFileKind, MAX_PRECOMPILED_SIZE,
+    MAX_PREPROCESSED_SIZE, MAX_REQUEST_SIZE,
+};
 use octobuild::cluster::common::{
-    BuilderInfo, BuilderInfoUpdate, RPC_BUILDER_TASK, RPC_BUILDER_UPDATE, RPC_BUILDER_UPLOAD,
+    has_encoding, http_client, http_server, protocol_error, read_body, tls_identity, write_body,
+    BuilderInfo, BuilderInfoUpdate, ADMIN_BUILDER_DRAIN, ENCODING_LZ4, PROTOCOL_VERSION,
+    RPC_BUILDER_TASK, RPC_BUILDER_UPDATE, RPC_BUILDER_UPLOAD,
 };
-use octobuild::compiler::CompileInput::Preprocessed;
-use octobuild::compiler::{
-    CompileStep, Compiler, CompilerOutput, PCHArgs, PCHUsage, SharedState, Toolchain,
+use octobuild::cluster::discovery::DiscoveryAnnouncer;
+use octobuild::cluster::metrics::{
+    Counter, Histogram, MetricsWriter, METRICS_CONTENT_TYPE, METRICS_PATH,
 };
+use octobuild::compiler::CompileInput::PreprocessedFile;
+use octobuild::compiler::{CompileStep, Compiler, PCHArgs, PCHUsage, SharedState, Toolchain};
 use octobuild::config::Config;
+use octobuild::io::pipe::pipe;
 use octobuild::io::tempfile::TempFile;
 use octobuild::simple::supported_compilers;
 use octobuild::version;
 
 struct BuilderService {
+    state: Arc<BuilderState>,
     done: Arc<AtomicBool>,
     server: Option<(JoinHandle<()>, mpsc::Sender<()>)>,
     announcer: Option<JoinHandle<()>>,
+    // Builder is announced to coordinator or multicast discovery
+    announced: bool,
 }
 
 struct BuilderState {
     name: String,
+    labels: Vec<String>,
     shared: SharedState,
     precompiled_dir: PathBuf,
     toolchains: HashMap<String, Arc<dyn Toolchain>>,
+    // Uploaded precompiled headers in use by uploads and tasks (by hash)
     precompiled: Mutex<HashMap<String, Arc<PrecompiledFile>>>,
+    // Max total size of uploaded precompiled headers
+    precompiled_limit: u64,
+    // Max time to keep unused precompiled header
+    precompiled_max_age: Duration,
+    // Max time to wait for running tasks on builder stop
+    drain_timeout: Duration,
+    // Count of task slots (maximum concurrently running compilations)
+    slots: usize,
+    // Cluster requests signing
+    auth: Option<ClusterAuth>,
+    // Max count of tasks waiting for a slot
+    queue_limit: usize,
+    // Count of received tasks in progress (running and waiting for a slot)
+    active: AtomicUsize,
+    // Builder doesn't accept new tasks and is hidden from clients
+    draining: AtomicBool,
+    // Count of coordinator update attempts
+    announces: AtomicUsize,
+    metrics: BuilderMetrics,
+}
+
+// Builder statistics exposed to Prometheus.
+struct BuilderMetrics {
+    tasks_received: Counter,
+    tasks_succeeded: Counter,
+    tasks_failed: Counter,
+    // Uncompressed task payload sizes
+    bytes_received: Counter,
+    bytes_sent: Counter,
+    precompiled_uploads: Counter,
+    compile_duration: Histogram,
+}
+
+// Accepted task, releases its place on drop.
+struct ActiveTask {
+    state: Arc<BuilderState>,
 }
 
+// Precompiled header is not evicted while anybody holds a reference to it.
 struct PrecompiledFile {
     lock: Mutex<()>,
 }
 
 const PRECOMPILED_SUFFIX: &str = ".pch";
+// Interval between builder cache and precompiled headers size limit checks.
+const CACHE_CLEANUP_INTERVAL: Duration = Duration::from_secs(600);
+// Upper bounds of compilation duration histogram buckets, in seconds.
+const COMPILE_DURATION_BUCKETS: &[f64] = &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+// Delay suggested to clients when builder is fully loaded.
+const RETRY_AFTER_SECS: u64 = 1;
+// Chunks buffered between response compression thread and server.
+const RESPONSE_PIPE_CAPACITY: usize = 16;
+// Max size of task request body.
+const MAX_TASK_SIZE: u64 = MAX_REQUEST_SIZE as u64 + MAX_PREPROCESSED_SIZE;
 
 impl BuilderService {
-    fn new() -> octobuild::Result<Self> {
+    // Start builder service. Previous service (on reload) releases its address just before
+    // new server is bound and finishes running tasks in background.
+    fn new(previous: Option<BuilderService>) -> octobuild::Result<Self> {
         let config = Config::load()?;
         info!("Helper bind to address: {}", config.helper_bind);
 
-        let state = Arc::new(BuilderState {
-            name: hostname::get()?.into_string().unwrap(),
-            shared: SharedState::new(&config)?,
-            toolchains: BuilderService::discover_toolchains(),
-            precompiled_dir: config.cache,
-            precompiled: Mutex::new(HashMap::new()),
-        });
+        let identity = tls_identity(&config)?;
+        let secure = identity.is_some();
+        let client = http_client(&config)?;
+        let state = Arc::new(BuilderState::new(
+            &config,
+            BuilderService::discover_toolchains(),
+        )?);
         let worker_state = state.clone();
 
-        let server = Server::new(config.helper_bind, move |request| {
-            router!(request,
-                (HEAD) [RPC_BUILDER_UPLOAD.to_string() + "/:hash"] => {
-                    try_or_400!(handle_upload(worker_state.clone(), request))
-                },
-                (POST) [RPC_BUILDER_UPLOAD.to_string() + "/:hash"] => {
-                    try_or_400!(handle_upload(worker_state.clone(), request))
-                },
-                (POST) [RPC_BUILDER_TASK] => {
-                    try_or_400!(handle_task(worker_state.clone(), request))
-                },
-                _ => Response::empty_404(),
-            )
-        })
-        .unwrap();
+        if let Some(mut previous) = previous {
+            previous.stop();
+            thread::spawn(move || drop(previous));
+        }
+        let server = http_server(config.helper_bind, identity, move |request| {
+            route(&worker_state, request)
+        })?;
 
         info!("Helper local address: {}", server.server_addr());
 
@@ -90,50 +147,135 @@ impl BuilderService {
             info!("- {toolchain}");
         }
 
+        let discovery = config
+            .cluster_discovery
+            .map(|group| DiscoveryAnnouncer::new(group, config.cluster_discovery_interface))
+            .transpose()?;
+        let announced = config.coordinator.is_some() || discovery.is_some();
+        if !announced {
+            warn!("Builder: neither coordinator nor discovery is configured, clients can't find builder");
+        }
+
         let done = Arc::new(AtomicBool::new(false));
         Ok(BuilderService {
+            state: state.clone(),
             announcer: Some(BuilderService::thread_announcer(
                 state,
-                config.coordinator.unwrap(),
+                client,
+                config.coordinator,
+                discovery,
                 done.clone(),
                 server.server_addr(),
+                secure,
             )),
             done,
             server: Some(server.stoppable()),
+            announced,
         })
     }
 
+    // Stop announcements and listening for new connections. Accepted requests are still handled.
+    fn stop(&mut self) {
+        self.done.store(true, Ordering::Relaxed);
+        if let Some(t) = self.announcer.take() {
+            t.join().unwrap();
+        }
+        if let Some((handle, sender)) = self.server.take() {
+            sender.send(()).unwrap();
+            handle.join().unwrap();
+        }
+    }
+
     fn thread_announcer(
         state: Arc<BuilderState>,
-        coordinator: reqwest::Url,
+        client: reqwest::blocking::Client,
+        coordinator: Option<reqwest::Url>,
+        discovery: Option<DiscoveryAnnouncer>,
         done: Arc<AtomicBool>,
         endpoint: SocketAddr,
+        secure: bool,
     ) -> JoinHandle<()> {
         thread::spawn(move || {
-            let info = BuilderInfoUpdate::new(BuilderInfo {
+            let mut info = BuilderInfoUpdate::new(BuilderInfo {
                 name: state.name.clone(),
                 version: version::VERSION.to_owned(),
+                protocol: PROTOCOL_VERSION,
                 endpoint: endpoint.to_string(),
                 toolchains: state.toolchain_names(),
+                cores: u32::try_from(num_cpus::get()).unwrap_or(u32::MAX),
+                slots: u32::try_from(state.slots).unwrap_or(u32::MAX),
+                free_slots: 0,
+                queue_length: 0,
+                secure,
+                labels: state.labels.clone(),
+                draining: false,
             });
 
-            let client = reqwest::blocking::Client::new();
+            let mut last_cleanup = Instant::now();
             while !done.load(Ordering::Relaxed) {
-                match client
-                    .post(coordinator.join(RPC_BUILDER_UPDATE).unwrap())
-                    .body(bincode::encode_to_vec(&info, bincode::config::standard()).unwrap())
-                    .send()
-                {
-                    Ok(_) => {}
-                    Err(e) => {
-                        info!("Builder: can't send info to coordinator: {e}");
+                let (free_slots, queue_length) = state.load();
+                info.info.free_slots = free_slots;
+                info.info.queue_length = queue_length;
+                info.info.draining = state.is_draining();
+                if let Some(coordinator) = &coordinator {
+                    BuilderService::send_update(&state, &client, coordinator, &info);
+                }
+                if let Some(discovery) = &discovery {
+                    if let Err(e) = discovery.announce(&info.info, state.auth.as_ref()) {
+                        info!("Builder: can't multicast announcement: {e}");
                     }
                 }
+                state.announces.fetch_add(1, Ordering::SeqCst);
+                if last_cleanup.elapsed() >= CACHE_CLEANUP_INTERVAL {
+                    last_cleanup = Instant::now();
+                    let state = state.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = state.shared.cache.cleanup() {
+                            info!("Builder: cache cleanup failed: {e}");
+                        }
+                        if let Err(e) = state.evict_precompiled() {
+                            info!("Builder: precompiled headers eviction failed: {e}");
+                        }
+                    });
+                }
                 thread::sleep(Duration::from_secs(1));
             }
         })
     }
 
+    fn send_update(
+        state: &BuilderState,
+        client: &reqwest::blocking::Client,
+        coordinator: &reqwest::Url,
+        info: &BuilderInfoUpdate,
+    ) {
+        let url = coordinator.join(RPC_BUILDER_UPDATE).unwrap();
+        let payload = bincode::encode_to_vec(info, bincode::config::standard()).unwrap();
+        let mut request = client.post(url.clone());
+        if let Some(auth) = &state.auth {
+            request = auth.apply(
+                request,
+                "POST",
+                &url,
+                payload.len() as u64,
+                &body_digest(&payload),
+            );
+        }
+        match request.body(payload).send() {
+            Ok(response) if !response.status().is_success() => {
+                info!(
+                    "Builder: coordinator rejected info ({}): {}",
+                    response.status(),
+                    response.text().unwrap_or_default()
+                );
+            }
+            Ok(_) => {}
+            Err(e) => {
+                info!("Builder: can't send info to coordinator: {e}");
+            }
+        }
+    }
+
     #[must_use]
     fn discover_toolchains() -> HashMap<String, Arc<dyn Toolchain>> {
         let compiler = supported_compilers();
@@ -145,22 +287,171 @@ impl BuilderService {
     }
 }
 
+fn route(state: &Arc<BuilderState>, request: &Request) -> Response {
+    router!(request,
+        (HEAD) [RPC_BUILDER_UPLOAD.to_string() + "/:hash"] => {
+            try_or_400!(handle_upload(state.clone(), request))
+        },
+        (POST) [RPC_BUILDER_UPLOAD.to_string() + "/:hash"] => {
+            try_or_400!(handle_upload(state.clone(), request))
+        },
+        (POST) [ADMIN_BUILDER_DRAIN] => {
+            handle_drain(state, request, true)
+        },
+        (DELETE) [ADMIN_BUILDER_DRAIN] => {
+            handle_drain(state, request, false)
+        },
+        (GET) [METRICS_PATH] => {
+            metrics(state)
+        },
+        (POST) [RPC_BUILDER_TASK] => {
+            // Let clients know that compressed requests are supported
+            try_or_400!(handle_task(state.clone(), request))
+                .with_additional_header("Accept-Encoding", ENCODING_LZ4)
+        },
+        _ => Response::empty_404(),
+    )
+}
+
+// Switch drain mode from admin endpoint, allowed from local host or with signed request.
+fn handle_drain(state: &BuilderState, request: &Request, draining: bool) -> Response {
+    if !request.remote_addr().ip().is_loopback() {
+        let Some(auth) = &state.auth else {
+            return Response::text("Admin requests are allowed only from local host")
+                .with_status_code(403);
+        };
+        if let Some(response) = auth.check(request, &body_digest(&[])) {
+            return response;
+        }
+    }
+    info!(
+        "Builder: drain mode {} by {}",
+        if draining { "enabled" } else { "disabled" },
+        request.remote_addr()
+    );
+    state.draining.store(draining, Ordering::SeqCst);
+    Response::text(format!(
+        "draining: {draining}, active tasks: {}\n",
+        state.active.load(Ordering::SeqCst)
+    ))
+}
+
 fn handle_task(state: Arc<BuilderState>, request: &Request) -> octobuild::Result<Response> {
     // Receive compilation request.
     info!("Received task from: {}", &request.remote_addr());
-    let request: CompileRequest =
-        bincode::decode_from_std_read(&mut request.data().unwrap(), bincode::config::standard())?;
-    let pch_usage: PCHUsage = match request.precompiled_hash {
+    if let Some(response) = protocol_error(request) {
+        return reject_task(request, response);
+    }
+    // Don't take a slot or process the body of requests with invalid header signature
+    let body_size = match state.auth.as_ref().map(|auth| auth.precheck(request)) {
+        Some(Err(response)) => {
+            info!(
+                "Rejected unauthenticated task from: {}",
+                request.remote_addr()
+            );
+            return reject_task(request, response);
+        }
+        Some(Ok(size)) => Some(size),
+        None => None,
+    };
+    if body_size.is_some_and(|size| size > MAX_TASK_SIZE) {
+        return reject_task(
+            request,
+            Response::text("Request is too large").with_status_code(413),
+        );
+    }
+    state.metrics.tasks_received.inc();
+    let Some(active) = state.try_accept() else {
+        let reason = if state.is_draining() {
+            "Builder is draining"
+        } else {
+            "Builder is busy"
+        };
+        info!("{reason}, rejecting task from: {}", &request.remote_addr());
+        return reject_task(
+            request,
+            Response::text(reason)
+                .with_status_code(503)
+                .with_additional_header("Retry-After", RETRY_AFTER_SECS.to_string()),
+        );
+    };
+    let compress = has_encoding(request.header("Accept-Encoding"), ENCODING_LZ4);
+    // Preprocessed data is stored in temporary file, so large units don't occupy builder memory
+    let preprocessed = TempFile::new_in(state.shared.temp_dir.path(), ".i");
+    let mut body_hasher = Sha256::new();
+    let mut preprocessed_hasher = Sha256::new();
+    let mut received = octobuild::io::counter::Counter::reader(read_body(
+        request.data().unwrap(),
+        has_encoding(request.header("Content-Encoding"), ENCODING_LZ4),
+    )?);
+    let mut body = tee::TeeReader::new(&mut received, &mut body_hasher);
+    let task: CompileRequest = match bincode::decode_from_std_read(
+        &mut body,
+        bincode::config::standard().with_limit::<MAX_REQUEST_SIZE>(),
+    ) {
+        Ok(task) => task,
+        Err(e) => {
+            return reject_body(
+                &mut body,
+                Response::text(format!("Invalid task: {e}")).with_status_code(400),
+            );
+        }
+    };
+    if task.preprocessed_size > MAX_PREPROCESSED_SIZE {
+        return reject_body(
+            &mut body,
+            Response::text("Preprocessed data is too large").with_status_code(413),
+        );
+    }
+    let written = std::io::copy(
+        &mut tee::TeeReader::new(
+            (&mut body).take(task.preprocessed_size),
+            &mut preprocessed_hasher,
+        ),
+        &mut File::create(preprocessed.path())?,
+    )?;
+    if written != task.preprocessed_size || body.read(&mut [0])? != 0 {
+        return reject_body(
+            &mut body,
+            Response::text("Unexpected preprocessed data size").with_status_code(400),
+        );
+    }
+    if body_size.is_some_and(|size| size != received.len() as u64) {
+        return Ok(Response::text("Unexpected request size").with_status_code(400));
+    }
+    state.metrics.bytes_received.add(received.len() as u64);
+    if let Some(response) = state
+        .auth
+        .as_ref()
+        .and_then(|auth| auth.check(request, &hex::encode(body_hasher.finalize())))
+    {
+        info!(
+            "Rejected unauthenticated task from: {}",
+            request.remote_addr()
+        );
+        return Ok(response);
+    }
+    let request = task;
+    let mut precompiled: Option<Arc<PrecompiledFile>> = None;
+    let pch_usage: PCHUsage = match &request.precompiled_hash {
         Some(hash) => {
-            if !is_valid_sha256(&hash) {
+            if !is_valid_sha256(hash) {
                 return Ok(
                     Response::text(format!("Invalid hash value: {hash}")).with_status_code(400)
                 );
             }
+            // Keep precompiled header from eviction until compilation is finished
+            let file = state.get_precompiled(hash);
             let path = state
                 .precompiled_dir
                 .join(hash.clone() + PRECOMPILED_SUFFIX);
-            if !path.exists() {
+            // Eviction deletes files under their lock, so file can't disappear after this check
+            let found = {
+                let _lock = file.lock.lock().unwrap();
+                touch(&path)
+            };
+            precompiled = Some(file);
+            if !found {
                 return Ok(
                     Response::text(format!("Precompiled file not found: {hash}"))
                         .with_status_code(424),
@@ -175,21 +466,278 @@ fn handle_task(state: Arc<BuilderState>, request: &Request) -> octobuild::Result
         }
         None => PCHUsage::None,
     };
+    let Some(toolchain) = state.toolchains.get(&request.toolchain).cloned() else {
+        info!("Requested unknown toolchain: {}", request.toolchain);
+        return compile_response(
+            &CompileResponse::UnknownToolchain(request.toolchain),
+            compress,
+            &state.metrics.bytes_sent,
+        );
+    };
+    let args = match toolchain.check_remote_args(&request.args, request.precompiled_output) {
+        Ok(args) => args,
+        Err(e) => {
+            info!("Rejected task arguments: {e}");
+            return Ok(Response::text(e).with_status_code(403));
+        }
+    };
+    if !request.object_output && !request.precompiled_output {
+        return Ok(Response::text("Task doesn't produce any files").with_status_code(400));
+    }
+    // Same units are often compiled for several clients, so serve them from builder cache
+    let cache_key = request.cache_key(&hex::encode(preprocessed_hasher.finalize()));
+    let object = TempFile::new_in(state.shared.temp_dir.path(), ".o");
+    let object_path = object.path().absolutize()?.to_path_buf();
+    let mut outputs = Vec::new();
+    if request.object_output {
+        outputs.push((OutputFileKind::Object, object_path.clone()));
+    }
+    let mut generated: Option<(TempFile, Arc<PrecompiledFile>)> = None;
+    let pch_usage = if request.precompiled_output {
+        if precompiled.is_some() {
+            return Ok(Response::text(
+                "Precompiled header can't be used and generated by same task",
+            )
+            .with_status_code(400));
+        }
+        // Don't generate same precompiled header in multiple threads.
+        let creating = state.get_precompiled(&cache_key);
+        let temporary = TempFile::new_in(&state.precompiled_dir, ".tmp");
+        let path = temporary.path().to_path_buf();
+        let path_abs = path.absolutize()?.to_path_buf();
+        outputs.push((OutputFileKind::Precompiled, path_abs.clone()));
+        generated = Some((temporary, creating));
+        PCHUsage::Out(PCHArgs {
+            path,
+            path_abs,
+            marker: None,
+        })
+    } else {
+        pch_usage
+    };
     let compile_step = CompileStep {
-        output_object: None,
+        output_object: request.object_output.then_some(object_path),
         pch_usage,
-        args: request.args.iter().map(OsString::from).collect(),
-        input: Preprocessed(CompilerOutput::Vec(request.preprocessed_data)),
+        args: args.iter().map(OsString::from).collect(),
+        input: PreprocessedFile(preprocessed.path().absolutize()?.to_path_buf()),
         run_second_cpp: false,
+        // Builder environment must not affect output of cached tasks
+        clear_env: true,
     };
 
-    let toolchain: Arc<dyn Toolchain> = state.toolchains.get(&request.toolchain).unwrap().clone();
-    let response = CompileResponse::from(toolchain.run_compile(&state.shared, compile_step));
-    let payload = bincode::encode_to_vec(&response, bincode::config::standard())?;
-    Ok(Response::from_data("application/octet-stream", payload))
+    let lock = generated
+        .as_ref()
+        .map(|(_, creating)| creating.lock.lock().unwrap());
+    let started = Instant::now();
+    let result = state.shared.cache.run_file_cached(
+        &state.shared.statistic,
+        &cache_key,
+        outputs.iter().map(|(_, path)| path.clone()).collect(),
+        || toolchain.run_compile(&state.shared, compile_step),
+    );
+    state.metrics.compile_duration.observe(started.elapsed());
+    drop(preprocessed);
+    let mut stream = OutputStream::default();
+    let response = match result {
+        Ok(output) => {
+            let mut files = Vec::new();
+            if output.success() {
+                for (kind, mut path) in outputs {
+                    if kind == OutputFileKind::Precompiled {
+                        let (seeded, guard) = seed_precompiled(&state, &path)?;
+                        path = seeded;
+                        stream.precompiled.push(guard);
+                    }
+                    let file = File::open(&path)?;
+                    let size = file.metadata()?.len();
+                    files.push(OutputFile { kind, size });
+                    stream.add_file(file, size);
+                }
+            }
+            CompileResponse::Success { output, files }
+        }
+        Err(e) => CompileResponse::Err(e.to_string()),
+    };
+    if matches!(&response, CompileResponse::Success { output, .. } if output.success()) {
+        state.metrics.tasks_succeeded.inc();
+    } else {
+        state.metrics.tasks_failed.inc();
+    }
+    drop(lock);
+    // Temporary files are removed after response is sent
+    stream.temporary.push(object);
+    if let Some((temporary, _)) = generated {
+        stream.temporary.push(temporary);
+    }
+    drop(precompiled);
+    stream.active = Some(active);
+    stream.into_response(&response, compress, &state.metrics.bytes_sent)
+}
+
+// Answer task without processing its body. Client may still be sending the body and sees
+// connection reset instead of response if it is left unread, so the body is skipped first.
+fn reject_task(request: &Request, response: Response) -> octobuild::Result<Response> {
+    match request.data() {
+        Some(data) => reject_body(data, response),
+        None => Ok(response),
+    }
+}
+
+// Same as `reject_task` for partially read task body.
+fn reject_body(body: impl Read, response: Response) -> octobuild::Result<Response> {
+    std::io::copy(&mut body.take(MAX_TASK_SIZE), &mut std::io::sink())?;
+    Ok(response)
+}
+
+// Keep generated precompiled header, so clients using it don't need to upload it.
+// Returns path of precompiled header to send and guard, which keeps it from eviction.
+fn seed_precompiled(
+    state: &BuilderState,
+    temporary: &Path,
+) -> octobuild::Result<(PathBuf, Arc<PrecompiledFile>)> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut File::open(temporary)?, &mut hasher)?;
+    let hash = hex::encode(hasher.finalize());
+    let precompiled = state.get_precompiled(&hash);
+    let lock = precompiled.lock.lock().unwrap();
+    let path = state.precompiled_dir.join(hash + PRECOMPILED_SUFFIX);
+    let path = if touch(&path) || fs::rename(temporary, &path).is_ok() {
+        path
+    } else {
+        temporary.to_path_buf()
+    };
+    drop(lock);
+    Ok((path, precompiled))
+}
+
+// Response body, which streams output files after encoded response.
+#[derive(Default)]
+struct OutputStream {
+    header: Cursor<Vec<u8>>,
+    files: VecDeque<File>,
+    size: u64,
+    // Must be dropped after files are closed
+    temporary: Vec<TempFile>,
+    precompiled: Vec<Arc<PrecompiledFile>>,
+    // Task slot is released after response is sent
+    active: Option<ActiveTask>,
+}
+
+impl OutputStream {
+    fn add_file(&mut self, file: File, size: u64) {
+        self.files.push_back(file);
+        self.size += size;
+    }
+
+    fn into_response(
+        mut self,
+        response: &CompileResponse,
+        compress: bool,
+        sent: &Counter,
+    ) -> octobuild::Result<Response> {
+        let header = bincode::encode_to_vec(response, bincode::config::standard())?;
+        self.size += header.len() as u64;
+        sent.add(self.size);
+        self.header = Cursor::new(header);
+        let mut result = Response::from_data("application/octet-stream", Vec::new());
+        if compress {
+            let (writer, reader) = pipe(RESPONSE_PIPE_CAPACITY);
+            thread::spawn(move || {
+                drop(write_body(writer, true, |writer| {
+                    std::io::copy(&mut self, writer).map(drop)
+                }));
+            });
+            result.data = ResponseBody::from_reader(reader);
+            return Ok(result.with_additional_header("Content-Encoding", ENCODING_LZ4));
+        }
+        let size =
+            usize::try_from(self.size).map_err(|e| octobuild::Error::Generic(e.to_string()))?;
+        result.data = ResponseBody::from_reader_and_size(self, size);
+        Ok(result)
+    }
+}
+
+impl Read for OutputStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let size = self.header.read(buf)?;
+        if size > 0 || buf.is_empty() {
+            return Ok(size);
+        }
+        while let Some(file) = self.files.front_mut() {
+            let size = file.read(buf)?;
+            if size > 0 {
+                return Ok(size);
+            }
+            self.files.pop_front();
+        }
+        Ok(0)
+    }
+}
+
+fn compile_response(
+    response: &CompileResponse,
+    compress: bool,
+    sent: &Counter,
+) -> octobuild::Result<Response> {
+    OutputStream::default().into_response(response, compress, sent)
+}
+
+fn metrics(state: &BuilderState) -> Response {
+    let metrics = &state.metrics;
+    let (free_slots, queue_length) = state.load();
+    let mut writer = MetricsWriter::default();
+    writer.counter(
+        "octobuild_builder_tasks_received_total",
+        "Received compilation tasks, including rejected ones.",
+        metrics.tasks_received.get(),
+    );
+    writer.counter(
+        "octobuild_builder_tasks_succeeded_total",
+        "Successfully compiled tasks.",
+        metrics.tasks_succeeded.get(),
+    );
+    writer.counter(
+        "octobuild_builder_tasks_failed_total",
+        "Tasks failed with compilation or builder error.",
+        metrics.tasks_failed.get(),
+    );
+    writer.counter(
+        "octobuild_builder_received_bytes_total",
+        "Uncompressed task request bytes.",
+        metrics.bytes_received.get(),
+    );
+    writer.counter(
+        "octobuild_builder_sent_bytes_total",
+        "Uncompressed task response bytes.",
+        metrics.bytes_sent.get(),
+    );
+    writer.counter(
+        "octobuild_builder_precompiled_uploads_total",
+        "Uploaded precompiled headers.",
+        metrics.precompiled_uploads.get(),
+    );
+    writer.histogram(
+        "octobuild_builder_compile_duration_seconds",
+        "Task compilation duration, including builder cache hits.",
+        &metrics.compile_duration,
+    );
+    writer.gauge(
+        "octobuild_builder_free_slots",
+        "Idle task slots.",
+        f64::from(free_slots),
+    );
+    writer.gauge(
+        "octobuild_builder_queue_length",
+        "Tasks waiting for a free slot.",
+        f64::from(queue_length),
+    );
+    Response::from_data(METRICS_CONTENT_TYPE, writer.finish())
 }
 
 fn handle_upload(state: Arc<BuilderState>, request: &Request) -> octobuild::Result<Response> {
+    if let Some(response) = protocol_error(request) {
+        return Ok(response);
+    }
     // Receive compilation request.
     let hash = match request.get_param("hash") {
         Some(v) => v,
@@ -200,6 +748,30 @@ fn handle_upload(state: Arc<BuilderState>, request: &Request) -> octobuild::Resu
     if !is_valid_sha256(&hash) {
         return Ok(Response::text(format!("Invalid hash value: {hash}")).with_status_code(400));
     }
+    // Uploaded content is checked against its hash, so the hash is used as body digest
+    let digest = if request.method() == "HEAD" {
+        body_digest(&[])
+    } else {
+        hash.clone()
+    };
+    if let Some(response) = state
+        .auth
+        .as_ref()
+        .and_then(|auth| auth.check(request, &digest))
+    {
+        info!(
+            "Rejected unauthenticated upload from: {}",
+            request.remote_addr()
+        );
+        return Ok(response);
+    }
+    if request
+        .header("Content-Length")
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .is_some_and(|size| size > MAX_PRECOMPILED_SIZE)
+    {
+        return Ok(Response::text("Precompiled header is too large").with_status_code(413));
+    }
     info!(
         "Received upload from ({}, {}): {} ",
         request.method(),
@@ -210,7 +782,7 @@ fn handle_upload(state: Arc<BuilderState>, request: &Request) -> octobuild::Resu
     let path = state
         .precompiled_dir
         .join(hash.clone() + PRECOMPILED_SUFFIX);
-    if path.exists() {
+    if touch(&path) {
         // File is already uploaded
         return Ok(Response::text("").with_status_code(202));
     }
@@ -238,8 +810,12 @@ fn handle_upload(state: Arc<BuilderState>, request: &Request) -> octobuild::Resu
         }
     };
 
-    let mut tee = tee::TeeReader::new(request.data().unwrap(), temp);
+    // Body without declared size is limited while receiving
+    let mut tee = tee::TeeReader::new(request.data().unwrap().take(MAX_PRECOMPILED_SIZE + 1), temp);
     let written = std::io::copy(&mut tee, &mut hasher)?;
+    if written > MAX_PRECOMPILED_SIZE {
+        return Ok(Response::text("Precompiled header is too large").with_status_code(413));
+    }
 
     if hex::encode(hasher.finalize()) != hash {
         return Ok(
@@ -257,23 +833,181 @@ fn handle_upload(state: Arc<BuilderState>, request: &Request) -> octobuild::Resu
         }
     }
     drop(lock);
+    state.metrics.precompiled_uploads.inc();
 
     Ok(Response::text(""))
 }
 
+// Mark file as recently used for eviction, returns false if file doesn't exist.
+fn touch(path: &Path) -> bool {
+    if !path.is_file() {
+        return false;
+    }
+    // File may be opened by compiler without write sharing (cl.exe on Windows), so update is best-effort
+    if let Err(e) = File::options()
+        .write(true)
+        .open(path)
+        .and_then(|file| file.set_modified(SystemTime::now()))
+    {
+        info!("Can't update modification time of {}: {e}", path.display());
+    }
+    true
+}
+
 fn is_valid_sha256(hash: &str) -> bool {
     hex::decode(hash)
         .ok()
-        .is_some_and(|v| v.len() == Sha256::new().output_size() * 2)
+        .is_some_and(|v| v.len() == Sha256::new().output_size())
 }
 
 impl BuilderState {
+    fn new(
+        config: &Config,
+        toolchains: HashMap<String, Arc<dyn Toolchain>>,
+    ) -> octobuild::Result<Self> {
+        Ok(BuilderState {
+            name: hostname::get()?.into_string().unwrap(),
+            labels: config.builder_labels.clone(),
+            shared: SharedState::new(config)?,
+            toolchains,
+            auth: ClusterAuth::new(config),
+            precompiled_dir: config.cache.clone(),
+            precompiled: Mutex::new(HashMap::new()),
+            precompiled_limit: config.builder_pch_limit_mb * 1024 * 1024,
+            precompiled_max_age: Duration::from_secs(config.builder_pch_max_age_hours * 3600),
+            drain_timeout: Duration::from_secs(config.builder_drain_timeout_secs),
+            slots: config.process_limit.max(1),
+            queue_limit: config.builder_queue_limit,
+            active: AtomicUsize::new(0),
+            draining: AtomicBool::new(false),
+            announces: AtomicUsize::new(0),
+            metrics: BuilderMetrics {
+                tasks_received: Counter::default(),
+                tasks_succeeded: Counter::default(),
+                tasks_failed: Counter::default(),
+                bytes_received: Counter::default(),
+                bytes_sent: Counter::default(),
+                precompiled_uploads: Counter::default(),
+                compile_duration: Histogram::new(COMPILE_DURATION_BUCKETS),
+            },
+        })
+    }
+
+    // Take a task slot or a place in queue, None if builder is fully loaded or draining.
+    fn try_accept(self: &Arc<Self>) -> Option<ActiveTask> {
+        let limit = self.slots + self.queue_limit;
+        self.active
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |active| {
+                (active < limit).then_some(active + 1)
+            })
+            .ok()?;
+        let task = ActiveTask {
+            state: self.clone(),
+        };
+        // Checked after taking a place, so drain never misses a task being accepted
+        if self.is_draining() {
+            return None;
+        }
+        Some(task)
+    }
+
+    fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    // Stop accepting tasks and wait until running tasks are finished and, if builder is announced,
+    // clients are notified.
+    fn drain(&self, timeout: Duration, announced: bool) -> bool {
+        self.draining.store(true, Ordering::SeqCst);
+        let announces = self.announces.load(Ordering::SeqCst);
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if self.active.load(Ordering::SeqCst) == 0
+                && (!announced || self.announces.load(Ordering::SeqCst) > announces)
+            {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        false
+    }
+
+    // Current free task slots and task queue length.
+    fn load(&self) -> (u32, u32) {
+        let active = self.active.load(Ordering::Relaxed);
+        (
+            u32::try_from(self.slots.saturating_sub(active)).unwrap_or(u32::MAX),
+            u32::try_from(active.saturating_sub(self.slots)).unwrap_or(u32::MAX),
+        )
+    }
+
     fn toolchain_names(&self) -> Vec<String> {
         let mut names: Vec<String> = self.toolchains.keys().cloned().collect();
         names.sort();
         names
     }
 
+    // Remove old precompiled headers over size limit or age, except ones in use.
+    fn evict_precompiled(&self) -> octobuild::Result<()> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.precompiled_dir)? {
+            let entry = entry?;
+            let Some(hash) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_suffix(PRECOMPILED_SUFFIX))
+                .map(str::to_string)
+            else {
+                continue;
+            };
+            let metadata = entry.metadata()?;
+            if metadata.is_file() {
+                files.push((metadata.modified()?, metadata.len(), hash, entry.path()));
+            }
+        }
+        // Keep recently used files
+        files.sort_by_key(|file| Reverse(file.0));
+
+        // Choose files under the map lock and reserve their entries, so files are deleted without holding it.
+        let now = SystemTime::now();
+        let mut evicted = Vec::new();
+        {
+            let mut precompiled = self.precompiled.lock().unwrap();
+            precompiled.retain(|_, file| Arc::strong_count(file) > 1);
+            let mut total: u64 = 0;
+            for (modified, size, hash, path) in files {
+                total += size;
+                if precompiled.contains_key(&hash) {
+                    continue;
+                }
+                let expired = now
+                    .duration_since(modified)
+                    .is_ok_and(|age| age > self.precompiled_max_age);
+                if expired || total > self.precompiled_limit {
+                    total -= size;
+                    let file = Arc::new(PrecompiledFile {
+                        lock: Mutex::new(()),
+                    });
+                    precompiled.insert(hash.clone(), file.clone());
+                    evicted.push((hash, path, file));
+                }
+            }
+        }
+        for (hash, path, file) in evicted {
+            let _lock = file.lock.lock().unwrap();
+            // Referenced only by map and eviction, otherwise taken by a task after it was chosen
+            if Arc::strong_count(&file) == 2 {
+                info!("Evicting precompiled header: {hash}");
+                fs::remove_file(path)?;
+            }
+        }
+        self.precompiled
+            .lock()
+            .unwrap()
+            .retain(|_, file| Arc::strong_count(file) > 1);
+        Ok(())
+    }
+
     fn get_precompiled(&self, hash: &str) -> Arc<PrecompiledFile> {
         self.precompiled
             .lock()
@@ -288,16 +1022,21 @@ impl BuilderState {
     }
 }
 
+impl Drop for ActiveTask {
+    fn drop(&mut self) {
+        self.state.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
 impl Drop for BuilderService {
     fn drop(&mut self) {
-        self.done.store(true, Ordering::Relaxed);
-        if let Some(t) = self.announcer.take() {
-            t.join().unwrap();
-        }
-        if let Some((handle, sender)) = self.server.take() {
-            sender.send(()).unwrap();
-            handle.join().unwrap();
+        info!("Builder: draining");
+        // Stopped announcer can't notify clients about draining
+        let announced = self.announced && self.announcer.is_some();
+        if !self.state.drain(self.state.drain_timeout, announced) {
+            info!("Builder: drain timeout, running tasks will fail");
         }
+        self.stop();
     }
 }
 
@@ -318,11 +1057,15 @@ fn main() {
                 match signal {
                     State::Start => {
                         info!("Builder: Starting");
-                        builder = Some(BuilderService::new());
+                        builder = Some(BuilderService::new(None));
                         info!("Builder: Ready");
                     }
                     State::Reload => {
+                        // Running tasks are finished in background, new ones go to new service
                         info!("Builder: Reload");
+                        let previous = builder.take().and_then(Result::ok);
+                        builder = Some(BuilderService::new(previous));
+                        info!("Builder: Ready");
                     }
                     State::Stop => {
                         info!("Builder: Stoping");
@@ -335,3 +1078,669 @@ fn main() {
         })
         .unwrap();
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::fs::File;
+    use std::io::{Cursor, Read};
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use rouille::{Request, Response};
+    use sha2::{Digest, Sha256};
+
+    use octobuild::cluster::auth::{
+        body_digest, ClusterAuth, BODY_SIZE_HEADER, HEADER_SIGNATURE_HEADER, SIGNATURE_HEADER,
+        TIMESTAMP_HEADER,
+    };
+    use octobuild::cluster::builder::{
+        CompileRequest, CompileResponse, OutputFileKind, MAX_PRECOMPILED_SIZE,
+        MAX_PREPROCESSED_SIZE, MAX_REQUEST_SIZE,
+    };
+    use octobuild::cluster::common::{
+        http_client, http_server, ADMIN_BUILDER_DRAIN, PROTOCOL_HEADER, PROTOCOL_VERSION,
+        RPC_BUILDER_TASK,
+    };
+    use octobuild::cluster::metrics::METRICS_PATH;
+    use octobuild::compiler::{
+        CommandInfo, CompilationTask, CompileStep, CompilerOutput, OutputInfo, PreprocessResult,
+        SharedState, Toolchain,
+    };
+    use octobuild::config::Config;
+
+    use crate::{handle_upload, route, BuilderState, PRECOMPILED_SUFFIX};
+
+    fn builder() -> (tempfile::TempDir, Arc<BuilderState>) {
+        builder_with(HashMap::new())
+    }
+
+    fn builder_with(
+        toolchains: HashMap<String, Arc<dyn Toolchain>>,
+    ) -> (tempfile::TempDir, Arc<BuilderState>) {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache: dir.path().to_path_buf(),
+            ..Config::default()
+        };
+        let state = BuilderState::new(&config, toolchains).unwrap();
+        (dir, Arc::new(state))
+    }
+
+    fn request(method: &str, url: &str, data: Vec<u8>) -> Request {
+        Request::fake_http(
+            method,
+            url,
+            vec![(PROTOCOL_HEADER.to_string(), PROTOCOL_VERSION.to_string())],
+            data,
+        )
+    }
+
+    // Toolchain, which writes fixed object and precompiled header content.
+    struct FakeToolchain;
+
+    impl Toolchain for FakeToolchain {
+        fn identifier(&self) -> Option<String> {
+            None
+        }
+
+        fn create_tasks(
+            &self,
+            _: CommandInfo,
+            _: Vec<String>,
+            _: bool,
+        ) -> octobuild::Result<Vec<CompilationTask>> {
+            Err(octobuild::Error::from("Not supported by fake toolchain"))
+        }
+
+        fn run_preprocess(
+            &self,
+            _: &SharedState,
+            _: &CompilationTask,
+        ) -> octobuild::Result<PreprocessResult> {
+            Err(octobuild::Error::from("Not supported by fake toolchain"))
+        }
+
+        fn create_compile_step(
+            &self,
+            _: &CompilationTask,
+            _: CompilerOutput,
+        ) -> octobuild::Result<CompileStep> {
+            Err(octobuild::Error::from("Not supported by fake toolchain"))
+        }
+
+        fn check_remote_args(
+            &self,
+            args: &[String],
+            output_precompiled: bool,
+        ) -> Result<Vec<String>, String> {
+            octobuild::clang::prepare::check_remote_args(args, output_precompiled)
+        }
+
+        fn run_compile(&self, _: &SharedState, task: CompileStep) -> octobuild::Result<OutputInfo> {
+            // Builder environment variables (CPATH, etc.) must not change output
+            if !task.clear_env {
+                return Err(octobuild::Error::Generic(
+                    "Compiler inherits builder environment".to_string(),
+                ));
+            }
+            if let Some(path) = task.output_object {
+                std::fs::write(path, b"object")?;
+            }
+            if let Some(path) = task.pch_usage.get_out_abs() {
+                std::fs::write(path, b"precompiled")?;
+            }
+            Ok(OutputInfo {
+                status: Some(0),
+                stdout: Vec::new(),
+                stderr: Vec::new(),
+            })
+        }
+    }
+
+    const PREPROCESSED: &[u8] = b"int main() { return 0; }";
+
+    fn task(toolchain: &str, precompiled_hash: Option<&str>) -> Vec<u8> {
+        encode_task(&new_task(toolchain, precompiled_hash))
+    }
+
+    fn new_task(toolchain: &str, precompiled_hash: Option<&str>) -> CompileRequest {
+        CompileRequest {
+            toolchain: toolchain.to_string(),
+            args: Vec::new(),
+            preprocessed_size: PREPROCESSED.len() as u64,
+            precompiled_hash: precompiled_hash.map(str::to_string),
+            precompiled_output: false,
+            object_output: true,
+        }
+    }
+
+    fn encode_task(request: &CompileRequest) -> Vec<u8> {
+        let mut data = bincode::encode_to_vec(request, bincode::config::standard()).unwrap();
+        data.extend_from_slice(PREPROCESSED);
+        data
+    }
+
+    fn body(response: Response) -> Vec<u8> {
+        let mut data = Vec::new();
+        let (mut reader, _) = response.data.into_reader_and_size();
+        reader.read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_unknown_toolchain() {
+        let (_dir, state) = builder();
+        let response = route(
+            &state,
+            &request("POST", RPC_BUILDER_TASK, task("clang-missing", None)),
+        );
+        assert_eq!(response.status_code, 200);
+        let (result, _): (CompileResponse, usize) =
+            bincode::decode_from_slice(&body(response), bincode::config::standard()).unwrap();
+        assert!(
+            matches!(result, CompileResponse::UnknownToolchain(name) if name == "clang-missing")
+        );
+    }
+
+    #[test]
+    fn test_remote_args() {
+        let (_dir, state) = builder_with(HashMap::from([(
+            "fake".to_string(),
+            Arc::new(FakeToolchain) as Arc<dyn Toolchain>,
+        )]));
+        let status = |args: &[&str]| {
+            let task = CompileRequest {
+                args: args.iter().map(|arg| (*arg).to_string()).collect(),
+                ..new_task("fake", None)
+            };
+            route(
+                &state,
+                &request("POST", RPC_BUILDER_TASK, encode_task(&task)),
+            )
+            .status_code
+        };
+        assert_eq!(status(&["-x", "c++", "-Wall", "-Wno-unused"]), 200);
+        // Driver forwards these options to other tools without validation
+        assert_eq!(status(&["-x", "c++", "-Wp,-load,evil.so"]), 403);
+        assert_eq!(status(&["-x", "c++", "-Wp,-MD,evil.d"]), 403);
+        assert_eq!(status(&["-x", "c++", "-Wa,-aln=evil.lst"]), 403);
+        assert_eq!(status(&["-x", "c++", "-Wl,-plugin,evil.so"]), 403);
+        assert_eq!(state.active.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_metrics() {
+        let (_dir, state) = builder();
+        route(
+            &state,
+            &request("POST", RPC_BUILDER_TASK, task("clang-missing", None)),
+        );
+        let response = route(&state, &request("GET", METRICS_PATH, Vec::new()));
+        assert_eq!(response.status_code, 200);
+        let text = String::from_utf8(body(response)).unwrap();
+        assert!(text.contains("\noctobuild_builder_tasks_received_total 1\n"));
+        assert!(text.contains("\n# TYPE octobuild_builder_compile_duration_seconds histogram\n"));
+    }
+
+    #[test]
+    fn test_truncated_task() {
+        let (_dir, state) = builder();
+        let mut data = task("clang-missing", None);
+        data.truncate(data.len() / 2);
+        let response = route(&state, &request("POST", RPC_BUILDER_TASK, data));
+        assert_eq!(response.status_code, 400);
+
+        // Preprocessed data doesn't match declared size
+        let mut data = task("clang-missing", None);
+        data.push(0);
+        let response = route(&state, &request("POST", RPC_BUILDER_TASK, data));
+        assert_eq!(response.status_code, 400);
+    }
+
+    #[test]
+    fn test_task_limits() {
+        let (_dir, state) = builder();
+        let mut task = CompileRequest {
+            toolchain: "clang-missing".to_string(),
+            args: Vec::new(),
+            preprocessed_size: MAX_PREPROCESSED_SIZE + 1,
+            precompiled_hash: None,
+            precompiled_output: false,
+            object_output: true,
+        };
+        let encode = |task: &CompileRequest| {
+            bincode::encode_to_vec(task, bincode::config::standard()).unwrap()
+        };
+        let response = route(&state, &request("POST", RPC_BUILDER_TASK, encode(&task)));
+        assert_eq!(response.status_code, 413);
+
+        // Request header is decoded with size limit
+        task.preprocessed_size = 0;
+        task.args = vec!["-D".repeat(MAX_REQUEST_SIZE / 2)];
+        let response = route(&state, &request("POST", RPC_BUILDER_TASK, encode(&task)));
+        assert_eq!(response.status_code, 400);
+    }
+
+    #[test]
+    fn test_unsigned_task() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache: dir.path().to_path_buf(),
+            cluster_token: Some("secret".to_string()),
+            ..Config::default()
+        };
+        let state = Arc::new(BuilderState::new(&config, HashMap::new()).unwrap());
+        let response = route(
+            &state,
+            &request("POST", RPC_BUILDER_TASK, task("clang-missing", None)),
+        );
+        assert_eq!(response.status_code, 401);
+        // Rejected before taking a slot or reading the body
+        assert_eq!(state.metrics.tasks_received.get(), 0);
+        assert_eq!(state.active.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_signed_task() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache: dir.path().to_path_buf(),
+            cluster_token: Some("secret".to_string()),
+            ..Config::default()
+        };
+        let state = Arc::new(BuilderState::new(&config, HashMap::new()).unwrap());
+        let signed = |auth: &ClusterAuth, data: Vec<u8>, body_size: u64| {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            Request::fake_http(
+                "POST",
+                RPC_BUILDER_TASK,
+                vec![
+                    (PROTOCOL_HEADER.to_string(), PROTOCOL_VERSION.to_string()),
+                    (TIMESTAMP_HEADER.to_string(), timestamp.to_string()),
+                    (BODY_SIZE_HEADER.to_string(), body_size.to_string()),
+                    (
+                        HEADER_SIGNATURE_HEADER.to_string(),
+                        auth.sign_header("POST", RPC_BUILDER_TASK, timestamp, body_size),
+                    ),
+                    (
+                        SIGNATURE_HEADER.to_string(),
+                        auth.sign("POST", RPC_BUILDER_TASK, timestamp, &body_digest(&data)),
+                    ),
+                ],
+                data,
+            )
+        };
+        let auth = ClusterAuth::from_token("secret");
+        let data = task("clang-missing", None);
+        let size = data.len() as u64;
+
+        // Forged header signature is rejected before taking a slot or reading the body
+        let response = route(
+            &state,
+            &signed(&ClusterAuth::from_token("other"), data.clone(), size),
+        );
+        assert_eq!(response.status_code, 401);
+        assert_eq!(state.metrics.tasks_received.get(), 0);
+
+        // Declared size is limited before taking a slot
+        let response = route(&state, &signed(&auth, data.clone(), u64::MAX));
+        assert_eq!(response.status_code, 413);
+        assert_eq!(state.metrics.tasks_received.get(), 0);
+
+        // Body must match declared size
+        let response = route(&state, &signed(&auth, data.clone(), size + 1));
+        assert_eq!(response.status_code, 400);
+
+        let response = route(&state, &signed(&auth, data, size));
+        assert_eq!(response.status_code, 200);
+        assert_eq!(state.active.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_bad_hash() {
+        let (_dir, state) = builder();
+        let response = route(
+            &state,
+            &request(
+                "POST",
+                RPC_BUILDER_TASK,
+                task("clang-missing", Some("../../etc")),
+            ),
+        );
+        assert_eq!(response.status_code, 400);
+
+        let response = handle_upload(
+            state,
+            &request("HEAD", "/rpc/v1/builder/upload?hash=../../etc", Vec::new()),
+        )
+        .unwrap();
+        assert_eq!(response.status_code, 400);
+    }
+
+    #[test]
+    fn test_upload_limit() {
+        let (_dir, state) = builder();
+        let hash = hex::encode(Sha256::digest(b"precompiled"));
+        let url = format!("/rpc/v1/builder/upload?hash={hash}");
+        let upload = |size: u64| {
+            Request::fake_http(
+                "POST",
+                url.clone(),
+                vec![
+                    (PROTOCOL_HEADER.to_string(), PROTOCOL_VERSION.to_string()),
+                    ("Content-Length".to_string(), size.to_string()),
+                ],
+                b"precompiled".to_vec(),
+            )
+        };
+
+        // Declared size is checked before receiving the file
+        let response = handle_upload(state.clone(), &upload(MAX_PRECOMPILED_SIZE + 1)).unwrap();
+        assert_eq!(response.status_code, 413);
+        assert!(!state
+            .precompiled_dir
+            .join(hash.clone() + PRECOMPILED_SUFFIX)
+            .exists());
+
+        let response = handle_upload(state.clone(), &upload(11)).unwrap();
+        assert_eq!(response.status_code, 200);
+        assert!(state
+            .precompiled_dir
+            .join(hash + PRECOMPILED_SUFFIX)
+            .exists());
+    }
+
+    #[test]
+    fn test_missing_protocol() {
+        let (_dir, state) = builder();
+        let response = route(
+            &state,
+            &Request::fake_http("POST", RPC_BUILDER_TASK, Vec::new(), task("clang", None)),
+        );
+        assert_eq!(response.status_code, 400);
+    }
+
+    #[test]
+    // Send task body through real HTTP server and client.
+    fn send_streamed(
+        config: &Config,
+        state: BuilderState,
+        body: impl Read + Send + 'static,
+    ) -> reqwest::blocking::Response {
+        let state = Arc::new(state);
+        let server = http_server("127.0.0.1:0".parse().unwrap(), None, move |request| {
+            route(&state, request)
+        })
+        .unwrap();
+        let url = format!("http://{}{RPC_BUILDER_TASK}", server.server_addr());
+        let (handle, stop) = server.stoppable();
+        let response = http_client(config)
+            .unwrap()
+            .post(url)
+            .header(PROTOCOL_HEADER, PROTOCOL_VERSION)
+            .body(reqwest::blocking::Body::new(body))
+            .send()
+            .unwrap();
+        stop.send(()).unwrap();
+        handle.join().unwrap();
+        response
+    }
+
+    #[test]
+    fn test_busy_streamed_task() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache: dir.path().to_path_buf(),
+            ..Config::default()
+        };
+        let mut state = BuilderState::new(&config, HashMap::new()).unwrap();
+        state.slots = 0;
+        state.queue_limit = 0;
+
+        // Body is larger than socket buffers, so client is still sending it when builder answers
+        let response = send_streamed(&config, state, std::io::repeat(0).take(64 << 20));
+        assert_eq!(response.status(), 503);
+        assert!(response.headers().contains_key("Retry-After"));
+    }
+
+    #[test]
+    fn test_oversized_streamed_task() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache: dir.path().to_path_buf(),
+            ..Config::default()
+        };
+        let state = BuilderState::new(&config, HashMap::new()).unwrap();
+        let task = CompileRequest {
+            preprocessed_size: MAX_PREPROCESSED_SIZE + 1,
+            ..new_task("clang-missing", None)
+        };
+        let header = bincode::encode_to_vec(&task, bincode::config::standard()).unwrap();
+
+        // Task slot is already taken when declared size is checked
+        let response = send_streamed(
+            &config,
+            state,
+            Cursor::new(header).chain(std::io::repeat(0).take(64 << 20)),
+        );
+        assert_eq!(response.status(), 413);
+    }
+
+    #[test]
+    fn test_drain() {
+        let (_dir, state) = builder();
+        let response = route(&state, &request("POST", ADMIN_BUILDER_DRAIN, Vec::new()));
+        assert_eq!(response.status_code, 200);
+        assert!(state.is_draining());
+
+        let response = route(
+            &state,
+            &request("POST", RPC_BUILDER_TASK, task("clang-missing", None)),
+        );
+        assert_eq!(response.status_code, 503);
+        assert_eq!(state.active.load(Ordering::SeqCst), 0);
+
+        let response = route(&state, &request("DELETE", ADMIN_BUILDER_DRAIN, Vec::new()));
+        assert_eq!(response.status_code, 200);
+        let response = route(
+            &state,
+            &request("POST", RPC_BUILDER_TASK, task("clang-missing", None)),
+        );
+        assert_eq!(response.status_code, 200);
+
+        // Remote admin requests require cluster token
+        let response = route(
+            &state,
+            &Request::fake_http_from(
+                "10.0.0.1:12345".parse().unwrap(),
+                "POST",
+                ADMIN_BUILDER_DRAIN,
+                Vec::new(),
+                Vec::new(),
+            ),
+        );
+        assert_eq!(response.status_code, 403);
+        assert!(!state.is_draining());
+    }
+
+    #[test]
+    fn test_drain_announced() {
+        let (_dir, state) = builder();
+        // Builder without coordinator and discovery doesn't wait for announcement
+        assert!(state.drain(Duration::from_secs(60), false));
+        // Announced builder waits until clients see draining state
+        assert!(!state.drain(Duration::from_millis(200), true));
+        let announcer = state.clone();
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            announcer.announces.fetch_add(1, Ordering::SeqCst);
+        });
+        assert!(state.drain(Duration::from_secs(60), true));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_evict_precompiled() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache: dir.path().to_path_buf(),
+            builder_pch_limit_mb: 1,
+            ..Config::default()
+        };
+        let state = BuilderState::new(&config, HashMap::new()).unwrap();
+        let now = SystemTime::now();
+        let pch = |hash: &str, age_secs: u64| {
+            let path = dir.path().join(hash.to_string() + PRECOMPILED_SUFFIX);
+            let file = File::create(&path).unwrap();
+            file.set_len(400 * 1024).unwrap();
+            file.set_modified(now - Duration::from_secs(age_secs))
+                .unwrap();
+            path
+        };
+        let newest = pch("aa", 0);
+        let in_use = pch("bb", 60);
+        let oldest = pch("cc", 120);
+        let expired = pch("dd", 3 * 24 * 3600);
+        let other = dir.path().join("other.txt");
+        File::create(&other).unwrap();
+
+        let used = state.get_precompiled("bb");
+        state.get_precompiled("ee");
+        state.evict_precompiled().unwrap();
+
+        assert!(newest.exists());
+        assert!(in_use.exists());
+        // Over size limit
+        assert!(!oldest.exists());
+        assert!(!expired.exists());
+        assert!(other.exists());
+        // Unused entries are dropped from lock map
+        assert_eq!(
+            state.precompiled.lock().unwrap().keys().collect::<Vec<_>>(),
+            ["bb"]
+        );
+        drop(used);
+    }
+
+    #[test]
+    fn test_precompiled_output() {
+        let (_dir, state) = builder_with(HashMap::from([(
+            "fake".to_string(),
+            Arc::new(FakeToolchain) as Arc<dyn Toolchain>,
+        )]));
+
+        let generate = CompileRequest {
+            precompiled_output: true,
+            ..new_task("fake", None)
+        };
+        let response = route(
+            &state,
+            &request("POST", RPC_BUILDER_TASK, encode_task(&generate)),
+        );
+        assert_eq!(response.status_code, 200);
+        let data = body(response);
+        let (result, offset): (CompileResponse, usize) =
+            bincode::decode_from_slice(&data, bincode::config::standard()).unwrap();
+        let CompileResponse::Success { output, files } = result else {
+            panic!("Unexpected compile response");
+        };
+        assert!(output.success());
+        assert_eq!(
+            files
+                .iter()
+                .map(|file| (file.kind, file.size))
+                .collect::<Vec<_>>(),
+            [
+                (OutputFileKind::Object, 6),
+                (OutputFileKind::Precompiled, 11)
+            ]
+        );
+        assert_eq!(&data[offset..], b"objectprecompiled");
+
+        // Generated header is kept for following tasks
+        let hash = hex::encode(Sha256::digest(b"precompiled"));
+        assert!(state
+            .precompiled_dir
+            .join(hash.clone() + PRECOMPILED_SUFFIX)
+            .exists());
+        let response = route(
+            &state,
+            &request("POST", RPC_BUILDER_TASK, task("fake", Some(&hash))),
+        );
+        assert_eq!(response.status_code, 200);
+        let (result, _): (CompileResponse, usize) =
+            bincode::decode_from_slice(&body(response), bincode::config::standard()).unwrap();
+        assert!(matches!(result, CompileResponse::Success { files, .. } if files.len() == 1));
+    }
+
+    #[test]
+    fn test_precompiled_without_object() {
+        let (_dir, state) = builder_with(HashMap::from([(
+            "fake".to_string(),
+            Arc::new(FakeToolchain) as Arc<dyn Toolchain>,
+        )]));
+
+        // Clang builds precompiled header without object file
+        let generate = CompileRequest {
+            precompiled_output: true,
+            object_output: false,
+            ..new_task("fake", None)
+        };
+        let response = route(
+            &state,
+            &request("POST", RPC_BUILDER_TASK, encode_task(&generate)),
+        );
+        assert_eq!(response.status_code, 200);
+        let data = body(response);
+        let (result, offset): (CompileResponse, usize) =
+            bincode::decode_from_slice(&data, bincode::config::standard()).unwrap();
+        let CompileResponse::Success { files, .. } = result else {
+            panic!("Unexpected compile response");
+        };
+        assert_eq!(
+            files
+                .iter()
+                .map(|file| (file.kind, file.size))
+                .collect::<Vec<_>>(),
+            [(OutputFileKind::Precompiled, 11)]
+        );
+        assert_eq!(&data[offset..], b"precompiled");
+        let hash = hex::encode(Sha256::digest(b"precompiled"));
+        assert!(state
+            .precompiled_dir
+            .join(hash + PRECOMPILED_SUFFIX)
+            .exists());
+
+        let empty = CompileRequest {
+            object_output: false,
+            ..new_task("fake", None)
+        };
+        let response = route(
+            &state,
+            &request("POST", RPC_BUILDER_TASK, encode_task(&empty)),
+        );
+        assert_eq!(response.status_code, 400);
+    }
+
+    #[test]
+    fn test_slot_released_after_response() {
+        let (_dir, state) = builder_with(HashMap::from([(
+            "fake".to_string(),
+            Arc::new(FakeToolchain) as Arc<dyn Toolchain>,
+        )]));
+        let response = route(
+            &state,
+            &request("POST", RPC_BUILDER_TASK, task("fake", None)),
+        );
+        assert_eq!(response.status_code, 200);
+        // Drain must wait until output files are sent
+        assert_eq!(state.active.load(Ordering::SeqCst), 1);
+        body(response);
+        assert_eq!(state.active.load(Ordering::SeqCst), 0);
+    }
+}
@@ -1,4 +1,6 @@
-use std::net::SocketAddr;
+use std::collections::HashMap;
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 use std::sync::mpsc::Receiver;
 use std::sync::{Arc, RwLock};
@@ -8,34 +10,261 @@ use daemon::Daemon;
 use daemon::DaemonRunner;
 use daemon::State;
 use log::info;
-use rouille::{router, try_or_400, Request, Response, Server};
+use rouille::{router, try_or_400, Request, Response};
 
+use octobuild::cluster::auth::{body_digest, ClusterAuth};
 use octobuild::cluster::common::{
-    BuilderInfo, BuilderInfoUpdate, RPC_BUILDER_LIST, RPC_BUILDER_UPDATE,
+    http_server, protocol_error, tls_identity, BuilderFailure, BuilderInfo, BuilderInfoUpdate,
+    COORDINATOR_STATUS, LABELS_PARAM, RPC_BUILDER_FAILURE, RPC_BUILDER_LIST, RPC_BUILDER_UPDATE,
 };
+use octobuild::cluster::metrics::{Counter, MetricsWriter, METRICS_CONTENT_TYPE, METRICS_PATH};
 use octobuild::config::Config;
 
 struct BuilderState {
     pub guid: String,
     pub info: BuilderInfo,
+    // Time of last builder update
+    pub updated: Instant,
     pub timeout: Instant,
+    // Builder is hidden from clients after failure reports until this time
+    pub failed_until: Option<Instant>,
+    // Recent failure reports by client address
+    pub failure_reports: HashMap<IpAddr, Instant>,
+}
+
+// Time to hide builder from clients after reported failure.
+const FAILURE_TIMEOUT: Duration = Duration::from_secs(10);
+// Failure reports are counted within this time.
+const FAILURE_REPORT_WINDOW: Duration = Duration::from_secs(60);
+// Builder is hidden only when this many clients report its failures, so one broken client can't hide it.
+const FAILURE_REPORTS_MIN: usize = 2;
+// Max size of builder update and failure report body.
+const MAX_RPC_BODY_SIZE: u64 = 1 << 20;
+
+impl BuilderState {
+    // Hide builder from clients when enough clients have recently reported its failures.
+    fn report_failure(&mut self, client: IpAddr, now: Instant) {
+        self.failure_reports
+            .retain(|_, reported| now.duration_since(*reported) < FAILURE_REPORT_WINDOW);
+        self.failure_reports.insert(client, now);
+        if self.failure_reports.len() >= FAILURE_REPORTS_MIN {
+            self.failed_until = Some(now + FAILURE_TIMEOUT);
+        }
+    }
+}
+
+// Builder state for status page.
+#[derive(serde::Serialize)]
+struct BuilderStatus<'a> {
+    name: &'a str,
+    endpoint: &'a str,
+    version: &'a str,
+    protocol: u32,
+    toolchains: &'a [String],
+    labels: &'a [String],
+    cores: u32,
+    slots: u32,
+    free_slots: u32,
+    queue_length: u32,
+    load: f64,
+    // Seconds since last builder update
+    last_heartbeat: f64,
+    // Builder is hidden from clients after failure reports
+    failed: bool,
+    // Builder is hidden from clients while finishing running tasks
+    draining: bool,
+}
+
+// Cluster state for status page.
+#[derive(serde::Serialize)]
+struct ClusterStatus<'a> {
+    builders: Vec<BuilderStatus<'a>>,
+    cores: u32,
+    slots: u32,
+    free_slots: u32,
+    queue_length: u32,
+}
+
+impl<'a> ClusterStatus<'a> {
+    fn new(holder: &'a [BuilderState], now: Instant) -> Self {
+        let builders: Vec<BuilderStatus> = holder
+            .iter()
+            .filter(|e| e.timeout >= now)
+            .map(|e| BuilderStatus {
+                name: &e.info.name,
+                endpoint: &e.info.endpoint,
+                version: &e.info.version,
+                protocol: e.info.protocol,
+                toolchains: &e.info.toolchains,
+                labels: &e.info.labels,
+                cores: e.info.cores,
+                slots: e.info.slots,
+                free_slots: e.info.free_slots,
+                queue_length: e.info.queue_length,
+                load: e.info.load(0),
+                last_heartbeat: now.duration_since(e.updated).as_secs_f64(),
+                failed: e.failed_until.is_some_and(|until| until > now),
+                draining: e.info.draining,
+            })
+            .collect();
+        // Failed and draining builders don't take tasks, so they aren't counted in capacity
+        let available = || builders.iter().filter(|e| !e.failed && !e.draining);
+        ClusterStatus {
+            cores: available().map(|e| e.cores).sum(),
+            slots: available().map(|e| e.slots).sum(),
+            free_slots: available().map(|e| e.free_slots).sum(),
+            queue_length: available().map(|e| e.queue_length).sum(),
+            builders,
+        }
+    }
+
+    fn to_html(&self) -> String {
+        let mut html = String::from(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+             <meta http-equiv=\"refresh\" content=\"5\">\n<title>octobuild cluster</title>\n\
+             <style>table { border-collapse: collapse; } th, td { border: 1px solid #ccc; padding: 4px 8px; }</style>\n\
+             </head>\n<body>\n<h1>octobuild cluster</h1>\n",
+        );
+        html += &format!(
+            "<p>Builders: {}, cores: {}, slots: {}, free slots: {}, queued tasks: {}</p>\n",
+            self.builders
+                .iter()
+                .filter(|e| !e.failed && !e.draining)
+                .count(),
+            self.cores,
+            self.slots,
+            self.free_slots,
+            self.queue_length
+        );
+        html += "<table>\n<tr><th>Name</th><th>Endpoint</th><th>Version</th><th>Toolchains</th><th>Labels</th>\
+                 <th>Cores</th><th>Slots</th><th>Free slots</th><th>Queue</th><th>Load</th><th>Last heartbeat</th>\
+                 <th>State</th></tr>\n";
+        for builder in &self.builders {
+            html += &format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td>\
+                 <td>{}</td><td>{:.2}</td><td>{:.1}s ago</td><td>{}</td></tr>\n",
+                escape_html(builder.name),
+                escape_html(builder.endpoint),
+                escape_html(builder.version),
+                escape_html(&builder.toolchains.join(", ")),
+                escape_html(&builder.labels.join(", ")),
+                builder.cores,
+                builder.slots,
+                builder.free_slots,
+                builder.queue_length,
+                builder.load,
+                builder.last_heartbeat,
+                if builder.failed {
+                    "failed"
+                } else if builder.draining {
+                    "draining"
+                } else {
+                    "ok"
+                },
+            );
+        }
+        html += "</table>\n</body>\n</html>\n";
+        html
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&#39;"),
+            c => result.push(c),
+        }
+    }
+    result
 }
 
 struct CoordinatorState {
     builders: RwLock<Vec<BuilderState>>,
+    // Cluster requests signing
+    auth: Option<ClusterAuth>,
+    // Accepted builder updates
+    registrations: Counter,
+    list_requests: Counter,
 }
 
 impl CoordinatorState {
-    pub fn new() -> Self {
+    pub fn new(config: &Config) -> Self {
         CoordinatorState {
             builders: RwLock::new(Vec::new()),
+            auth: ClusterAuth::new(config),
+            registrations: Counter::default(),
+            list_requests: Counter::default(),
+        }
+    }
+
+    // Read request body and verify request signature, returns error response on failure.
+    fn read_signed(&self, request: &Request) -> octobuild::Result<Result<Vec<u8>, Response>> {
+        // Requests with invalid header signature are rejected before reading body
+        let body_size = match self.auth.as_ref().map(|auth| auth.precheck(request)) {
+            Some(Err(response)) => {
+                info!(
+                    "Rejected unauthenticated request from: {}",
+                    request.remote_addr()
+                );
+                return Ok(Err(response));
+            }
+            Some(Ok(size)) => Some(size),
+            None => None,
+        };
+        let limit = body_size.unwrap_or(MAX_RPC_BODY_SIZE);
+        let mut payload = Vec::new();
+        if limit <= MAX_RPC_BODY_SIZE {
+            if let Some(data) = request.data() {
+                data.take(limit + 1).read_to_end(&mut payload)?;
+            }
+        }
+        if limit > MAX_RPC_BODY_SIZE || payload.len() as u64 > MAX_RPC_BODY_SIZE {
+            return Ok(Err(
+                Response::text("Request body is too large").with_status_code(413)
+            ));
+        }
+        if body_size.is_some_and(|size| size != payload.len() as u64) {
+            return Ok(Err(
+                Response::text("Unexpected request size").with_status_code(400)
+            ));
+        }
+        match self
+            .auth
+            .as_ref()
+            .and_then(|auth| auth.check(request, &body_digest(&payload)))
+        {
+            Some(response) => {
+                info!(
+                    "Rejected unauthenticated request from: {}",
+                    request.remote_addr()
+                );
+                Ok(Err(response))
+            }
+            None => Ok(Ok(payload)),
         }
     }
 }
 
 fn update(state: Arc<CoordinatorState>, request: &Request) -> octobuild::Result<Response> {
+    // Incompatible builders are not registered, so clients never see them.
+    if let Some(response) = protocol_error(request) {
+        info!(
+            "Rejected builder update from {}: incompatible protocol",
+            request.remote_addr()
+        );
+        return Ok(response);
+    }
+    let payload = match state.read_signed(request)? {
+        Ok(v) => v,
+        Err(response) => return Ok(response),
+    };
     let mut update: BuilderInfoUpdate =
-        bincode::decode_from_std_read(&mut request.data().unwrap(), bincode::config::standard())?;
+        bincode::decode_from_slice(&payload, bincode::config::standard())?.0;
     // Fix inspecified endpoint IP address.
     let endpoint = match SocketAddr::from_str(&update.info.endpoint) {
         Ok(v) => v,
@@ -50,30 +279,90 @@ fn update(state: Arc<CoordinatorState>, request: &Request) -> octobuild::Result<
             SocketAddr::new(request.remote_addr().ip(), endpoint.port()).to_string();
     }
 
+    state.registrations.inc();
     let payload: Vec<u8>;
     // Update information.
     {
         let mut holder = state.builders.write().unwrap();
         let now = Instant::now();
+        let previous = holder.iter_mut().find(|e| e.guid == update.guid);
+        let failed_until = previous
+            .as_ref()
+            .and_then(|e| e.failed_until)
+            .filter(|until| *until > now);
+        let failure_reports = previous
+            .map(|e| std::mem::take(&mut e.failure_reports))
+            .unwrap_or_default();
         holder.retain(|e| (e.guid != update.guid) && (e.timeout >= now));
         payload = bincode::encode_to_vec(&update.info, bincode::config::standard())?;
         holder.push(BuilderState {
             guid: update.guid,
             info: update.info,
+            updated: now,
             timeout: now + Duration::from_secs(5),
+            failed_until,
+            failure_reports,
         });
     }
 
     Ok(Response::from_data("application/octet-stream", payload))
 }
 
-fn list(state: Arc<CoordinatorState>) -> octobuild::Result<Response> {
+fn failure(state: Arc<CoordinatorState>, request: &Request) -> octobuild::Result<Response> {
+    if let Some(response) = protocol_error(request) {
+        return Ok(response);
+    }
+    let payload = match state.read_signed(request)? {
+        Ok(v) => v,
+        Err(response) => return Ok(response),
+    };
+    let report: BuilderFailure =
+        bincode::decode_from_slice(&payload, bincode::config::standard())?.0;
+    info!(
+        "Builder {} failure reported by: {}",
+        report.endpoint,
+        request.remote_addr()
+    );
+    let mut holder = state.builders.write().unwrap();
+    let now = Instant::now();
+    for builder in holder
+        .iter_mut()
+        .filter(|e| e.info.endpoint == report.endpoint)
+    {
+        builder.report_failure(request.remote_addr().ip(), now);
+    }
+    Ok(Response::text(""))
+}
+
+fn list(state: Arc<CoordinatorState>, request: &Request) -> octobuild::Result<Response> {
+    if let Some(response) = protocol_error(request) {
+        return Ok(response);
+    }
+    if let Err(response) = state.read_signed(request)? {
+        return Ok(response);
+    }
+    state.list_requests.inc();
+    // Clients may ask only for builders matching their label selector
+    let selector: Vec<String> = request
+        .get_param(LABELS_PARAM)
+        .map(|v| {
+            v.split(',')
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default();
     let holder = state.builders.read().unwrap();
     let now = Instant::now();
     let builders: Vec<&BuilderInfo> = holder
         .iter()
         .filter_map(|e| {
-            if e.timeout >= now {
+            if e.timeout >= now
+                && !e.info.draining
+                && e.failed_until.is_none_or(|until| until <= now)
+                && e.info.matches(&selector)
+            {
                 Some(&e.info)
             } else {
                 None
@@ -87,6 +376,52 @@ fn list(state: Arc<CoordinatorState>) -> octobuild::Result<Response> {
     ))
 }
 
+// Cluster state for admins: JSON for tools and HTML page for browsers.
+fn status(state: &CoordinatorState, html: bool) -> Response {
+    let holder = state.builders.read().unwrap();
+    let status = ClusterStatus::new(&holder, Instant::now());
+    if html {
+        return Response::html(status.to_html());
+    }
+    Response::json(&status)
+}
+
+fn metrics(state: &CoordinatorState) -> Response {
+    let now = Instant::now();
+    let (builders, failed) = {
+        let holder = state.builders.read().unwrap();
+        let alive = holder.iter().filter(|e| e.timeout >= now);
+        (
+            alive.clone().count(),
+            alive
+                .filter(|e| e.failed_until.is_some_and(|until| until > now))
+                .count(),
+        )
+    };
+    let mut writer = MetricsWriter::default();
+    writer.gauge(
+        "octobuild_coordinator_builders",
+        "Registered builders.",
+        builders as f64,
+    );
+    writer.gauge(
+        "octobuild_coordinator_failed_builders",
+        "Registered builders hidden from clients after failure reports.",
+        failed as f64,
+    );
+    writer.counter(
+        "octobuild_coordinator_registrations_total",
+        "Accepted builder updates.",
+        state.registrations.get(),
+    );
+    writer.counter(
+        "octobuild_coordinator_list_requests_total",
+        "Builder list requests from clients.",
+        state.list_requests.get(),
+    );
+    Response::from_data(METRICS_CONTENT_TYPE, writer.finish())
+}
+
 fn main() {
     env_logger::init();
 
@@ -106,19 +441,33 @@ fn main() {
                         let config = Config::load().unwrap();
                         info!("Coordinator bind to address: {}", config.coordinator_bind);
 
-                        let state = Arc::new(CoordinatorState::new());
-                        let server = Server::new(config.coordinator_bind, move |request| {
-                            router!(request,
-                                (GET) [RPC_BUILDER_LIST] => {
-                                    try_or_400!(list(state.clone()))
-                                },
-                                (POST) [RPC_BUILDER_UPDATE] => {
-                                    try_or_400!(update(state.clone(), request))
-                                },
-                                _ => Response::empty_404(),
-                            )
-                        })
-                        .unwrap();
+                        let state = Arc::new(CoordinatorState::new(&config));
+                        let identity = tls_identity(&config).unwrap();
+                        let server =
+                            http_server(config.coordinator_bind, identity, move |request| {
+                                router!(request,
+                                    (GET) ["/"] => {
+                                        status(&state, true)
+                                    },
+                                    (GET) [METRICS_PATH] => {
+                                        metrics(&state)
+                                    },
+                                    (GET) [COORDINATOR_STATUS] => {
+                                        status(&state, false)
+                                    },
+                                    (GET) [RPC_BUILDER_LIST] => {
+                                        try_or_400!(list(state.clone(), request))
+                                    },
+                                    (POST) [RPC_BUILDER_UPDATE] => {
+                                        try_or_400!(update(state.clone(), request))
+                                    },
+                                    (POST) [RPC_BUILDER_FAILURE] => {
+                                        try_or_400!(failure(state.clone(), request))
+                                    },
+                                    _ => Response::empty_404(),
+                                )
+                            })
+                            .unwrap();
 
                         web = Some(server.stoppable());
                         info!("Coordinator: Ready");
@@ -140,3 +489,195 @@ fn main() {
         })
         .unwrap();
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::io::Read;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+    use rouille::Request;
+
+    use octobuild::cluster::auth::{
+        ClusterAuth, BODY_SIZE_HEADER, HEADER_SIGNATURE_HEADER, TIMESTAMP_HEADER,
+    };
+    use octobuild::cluster::common::{
+        BuilderFailure, BuilderInfo, LABELS_PARAM, PROTOCOL_HEADER, PROTOCOL_VERSION,
+        RPC_BUILDER_FAILURE, RPC_BUILDER_LIST, RPC_BUILDER_UPDATE,
+    };
+    use octobuild::config::Config;
+
+    use crate::{
+        failure, list, update, BuilderState, ClusterStatus, CoordinatorState, MAX_RPC_BODY_SIZE,
+    };
+
+    fn builder(name: &str, cores: u32, free_slots: u32, failed: bool) -> BuilderState {
+        let now = Instant::now();
+        BuilderState {
+            guid: name.to_string(),
+            info: BuilderInfo {
+                name: name.to_string(),
+                endpoint: "127.0.0.1:3000".to_string(),
+                version: "1.0".to_string(),
+                toolchains: vec!["clang-18".to_string()],
+                cores,
+                slots: cores,
+                free_slots,
+                labels: vec!["linux".to_string()],
+                ..BuilderInfo::default()
+            },
+            updated: now,
+            timeout: now + Duration::from_secs(5),
+            failed_until: failed.then(|| now + Duration::from_secs(10)),
+            failure_reports: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_cluster_status() {
+        let holder = [
+            builder("server", 64, 60, false),
+            builder("laptop", 8, 8, false),
+            builder("broken", 32, 32, true),
+        ];
+        let status = ClusterStatus::new(&holder, Instant::now());
+        assert_eq!(status.builders.len(), 3);
+        assert_eq!(status.cores, 72);
+        assert_eq!(status.slots, 72);
+        assert_eq!(status.free_slots, 68);
+
+        let json: serde_json::Value = serde_json::to_value(&status).unwrap();
+        assert_eq!(json["builders"][2]["name"], "broken");
+        assert_eq!(json["builders"][2]["failed"], true);
+        assert_eq!(json["builders"][0]["toolchains"][0], "clang-18");
+    }
+
+    #[test]
+    fn test_list_labels() {
+        let state = Arc::new(CoordinatorState::new(&Config::default()));
+        let mut windows = builder("windows", 8, 8, false);
+        windows.info.labels = vec!["windows".to_string(), "workstation".to_string()];
+        state
+            .builders
+            .write()
+            .unwrap()
+            .extend([builder("linux", 8, 8, false), windows]);
+        let names = |url: &str| -> Vec<String> {
+            let request = Request::fake_http(
+                "GET",
+                url,
+                vec![(PROTOCOL_HEADER.to_string(), PROTOCOL_VERSION.to_string())],
+                Vec::new(),
+            );
+            let response = list(state.clone(), &request).unwrap();
+            let mut data = Vec::new();
+            let (mut reader, _) = response.data.into_reader_and_size();
+            reader.read_to_end(&mut data).unwrap();
+            let (builders, _): (Vec<BuilderInfo>, usize) =
+                bincode::decode_from_slice(&data, bincode::config::standard()).unwrap();
+            builders.into_iter().map(|b| b.name).collect()
+        };
+        assert_eq!(names(RPC_BUILDER_LIST), ["linux", "windows"]);
+        assert_eq!(
+            names(&format!("{RPC_BUILDER_LIST}?{LABELS_PARAM}=windows")),
+            ["windows"]
+        );
+        assert_eq!(
+            names(&format!("{RPC_BUILDER_LIST}?{LABELS_PARAM}=!workstation")),
+            ["linux"]
+        );
+        // Draining builders are hidden from clients
+        state.builders.write().unwrap()[0].info.draining = true;
+        assert_eq!(names(RPC_BUILDER_LIST), ["windows"]);
+    }
+
+    #[test]
+    fn test_update_limits() {
+        let state = Arc::new(CoordinatorState::new(&Config::default()));
+        let request = Request::fake_http(
+            "POST",
+            RPC_BUILDER_UPDATE,
+            vec![(PROTOCOL_HEADER.to_string(), PROTOCOL_VERSION.to_string())],
+            vec![0; MAX_RPC_BODY_SIZE as usize + 1],
+        );
+        assert_eq!(update(state, &request).unwrap().status_code, 413);
+
+        // Unsigned update is rejected before reading body
+        let config = Config {
+            cluster_token: Some("secret".to_string()),
+            ..Config::default()
+        };
+        let state = Arc::new(CoordinatorState::new(&config));
+        let request = Request::fake_http(
+            "POST",
+            RPC_BUILDER_UPDATE,
+            vec![(PROTOCOL_HEADER.to_string(), PROTOCOL_VERSION.to_string())],
+            vec![0; MAX_RPC_BODY_SIZE as usize + 1],
+        );
+        assert_eq!(update(state.clone(), &request).unwrap().status_code, 401);
+
+        // Signed update with declared size over limit is rejected before reading body
+        let auth = ClusterAuth::from_token("secret");
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let size = MAX_RPC_BODY_SIZE + 1;
+        let request = Request::fake_http(
+            "POST",
+            RPC_BUILDER_UPDATE,
+            vec![
+                (PROTOCOL_HEADER.to_string(), PROTOCOL_VERSION.to_string()),
+                (TIMESTAMP_HEADER.to_string(), timestamp.to_string()),
+                (BODY_SIZE_HEADER.to_string(), size.to_string()),
+                (
+                    HEADER_SIGNATURE_HEADER.to_string(),
+                    auth.sign_header("POST", RPC_BUILDER_UPDATE, timestamp, size),
+                ),
+            ],
+            vec![0; size as usize],
+        );
+        assert_eq!(update(state, &request).unwrap().status_code, 413);
+    }
+
+    #[test]
+    fn test_status_html_escape() {
+        let holder = [builder("<script>", 4, 4, false)];
+        let html = ClusterStatus::new(&holder, Instant::now()).to_html();
+        assert!(html.contains("&lt;script&gt;"));
+        assert!(!html.contains("<script>"));
+    }
+
+    #[test]
+    fn test_failure_reports() {
+        let state = Arc::new(CoordinatorState::new(&Config::default()));
+        state
+            .builders
+            .write()
+            .unwrap()
+            .push(builder("linux", 8, 8, false));
+        let report = |client: &str| {
+            let payload = bincode::encode_to_vec(
+                &BuilderFailure {
+                    endpoint: "127.0.0.1:3000".to_string(),
+                },
+                bincode::config::standard(),
+            )
+            .unwrap();
+            let request = Request::fake_http_from(
+                client.parse().unwrap(),
+                "POST",
+                RPC_BUILDER_FAILURE,
+                vec![(PROTOCOL_HEADER.to_string(), PROTOCOL_VERSION.to_string())],
+                payload,
+            );
+            assert_eq!(failure(state.clone(), &request).unwrap().status_code, 200);
+            state.builders.read().unwrap()[0].failed_until.is_some()
+        };
+        // Single client may have its own problems
+        assert!(!report("10.0.0.1:5000"));
+        assert!(!report("10.0.0.1:5001"));
+        assert!(report("10.0.0.2:5000"));
+    }
+}
@@ -8,10 +8,10 @@ use std::{env, fs};
 
 use regex::Regex;
 
-use crate::compiler::CompileInput::{Preprocessed, Source};
+use crate::compiler::CompileInput::{Preprocessed, PreprocessedFile, Source};
 use crate::compiler::{
     Arg, CommandInfo, CompilationTask, CompileStep, Compiler, CompilerOutput, OutputInfo,
-    ParamForm, PreprocessResult, Scope, SharedState, Toolchain, ToolchainHolder,
+    ParamForm, PreprocessResult, Scope, SharedState, SourceInput, Toolchain, ToolchainHolder,
 };
 use crate::lazy::Lazy;
 use os_str_bytes::OsStrBytes;
@@ -204,48 +204,64 @@ impl Toolchain for ClangToolchain {
         preprocessed: CompilerOutput,
     ) -> crate::Result<CompileStep> {
         let mut args = vec![OsString::from("-x"), OsString::from(&task.language)];
+        // Preprocessed data already contains included files, so precompiled header
+        // doesn't need preprocessor arguments
         collect_args(
             &task.shared.args,
             Scope::Compiler,
             task.shared.run_second_cpp,
-            task.shared.pch_usage.is_some(),
+            false,
             &mut args,
         )?;
 
-        Ok(CompileStep::new(task, preprocessed, args))
+        let mut step = CompileStep::new(task, preprocessed, args);
+        if step.pch_usage.is_out() {
+            // Precompiled header is written instead of object file
+            step.output_object = None;
+        }
+        Ok(step)
+    }
+
+    fn check_remote_args(
+        &self,
+        args: &[String],
+        output_precompiled: bool,
+    ) -> Result<Vec<String>, String> {
+        super::prepare::check_remote_args(args, output_precompiled)
     }
 
     fn run_compile(&self, state: &SharedState, task: CompileStep) -> crate::Result<OutputInfo> {
         let mut args = task.args.clone();
         args.push(OsString::from("-c"));
         match &task.input {
-            Preprocessed(_) => args.push(OsString::from("-")),
+            // Compiled unit has no file name, so precompiled header doesn't reference input file
+            Preprocessed(_) | PreprocessedFile(_) => args.push(OsString::from("-")),
             Source(source) => args.push(normalize_path(&source.path)),
         };
 
         args.push(OsString::from("-o"));
-        match task.output_object {
+        match task.pch_usage.get_out_abs().or(task.output_object.as_ref()) {
             None => args.push(OsString::from("-")),
-            Some(v) => args.push(normalize_path(&v)),
+            Some(v) => args.push(normalize_path(v)),
         };
 
         // Run compiler.
         state.wrap_slow(|| {
             let mut command = Command::new(&self.path);
-            match &task.input {
-                Preprocessed(_) => {
-                    command.env_clear();
-                }
-                Source(source) => {
-                    if let Some(dir) = &source.current_dir {
-                        command.current_dir(dir);
-                    }
-                }
+            if task.clear_env {
+                command.env_clear();
+            }
+            if let Source(SourceInput {
+                current_dir: Some(dir),
+                ..
+            }) = &task.input
+            {
+                command.current_dir(dir);
             }
 
             command
                 .stdin(match &task.input {
-                    Preprocessed(_) => Stdio::piped(),
+                    Preprocessed(_) | PreprocessedFile(_) => Stdio::piped(),
                     Source(_) => Stdio::null(),
                 })
                 .stdout(Stdio::piped())
@@ -254,8 +270,14 @@ impl Toolchain for ClangToolchain {
             let response_file = state.do_response_file(args, &mut command)?;
             let mut child = command.spawn()?;
 
-            if let Preprocessed(preprocessed) = task.input {
-                preprocessed.copy(child.stdin.as_mut().unwrap())?;
+            match &task.input {
+                Preprocessed(preprocessed) => {
+                    preprocessed.copy(child.stdin.as_mut().unwrap())?;
+                }
+                PreprocessedFile(path) => {
+                    std::io::copy(&mut File::open(path)?, child.stdin.as_mut().unwrap())?;
+                }
+                Source(_) => {}
             }
 
             let output = child.wait_with_output()?;
@@ -296,6 +318,199 @@ fn clang_identifier(clang: &Path) -> Option<String> {
 
 #[cfg(test)]
 mod test {
+    use std::ffi::OsString;
+    use std::fs;
+    use std::fs::File;
+    use std::path::PathBuf;
+    use std::process::Command;
+
+    use crate::clang::compiler::ClangToolchain;
+    use crate::clang::prepare::{check_remote_args, create_tasks};
+    use crate::compiler::CompileInput::{Preprocessed, PreprocessedFile};
+    use crate::compiler::{
+        CommandInfo, CompileStep, CompilerOutput, PCHArgs, PCHUsage, PreprocessResult, SharedState,
+        Toolchain,
+    };
+    use crate::config::Config;
+
+    // Tests with real compiler are skipped, if clang isn't installed.
+    fn clang_available() -> bool {
+        Command::new("clang++")
+            .arg("--version")
+            .output()
+            .is_ok_and(|output| output.status.success())
+    }
+
+    #[test]
+    fn test_ue_linux_remote_args() {
+        // Unreal Engine Linux toolchain command line (from response file)
+        let args: Vec<String> = "-c -pipe \
+            --sysroot=/opt/UnrealToolchain/v22_clang-16.0.6-centos7/x86_64-unknown-linux-gnu \
+            --gcc-toolchain=/opt/UnrealToolchain/v22_clang-16.0.6-centos7/x86_64-unknown-linux-gnu \
+            -target x86_64-unknown-linux-gnu -nostdinc++ \
+            -isystem/opt/UnrealEngine/Engine/Source/ThirdParty/Unix/LibCxx/include/c++/v1 \
+            -Wall -Werror -Wno-unused-private-field -fdiagnostics-format=msvc \
+            -fmessage-length=0 -fno-math-errno -fno-rtti -fno-exceptions \
+            -fvisibility-ms-compat -fvisibility-inlines-hidden -ffunction-sections \
+            -fdata-sections -gdwarf-4 -O3 -DPLATFORM_EXCEPTIONS_DISABLED=1 \
+            -DUE_BUILD_DEVELOPMENT=1 -I/opt/UnrealEngine/Engine/Source/Runtime/Core/Public \
+            -std=c++20 -x c++ -o Module.Core.1.cpp.o Module.Core.1.cpp"
+            .split_whitespace()
+            .map(str::to_string)
+            .collect();
+        let tasks =
+            create_tasks(CommandInfo::simple(PathBuf::from("clang++")), args, false).unwrap();
+        assert_eq!(tasks.len(), 1);
+
+        let step = ClangToolchain::new(PathBuf::from("clang++"))
+            .create_compile_step(&tasks[0], CompilerOutput::Vec(Vec::new()))
+            .unwrap();
+        let args: Vec<String> = step
+            .args
+            .iter()
+            .map(|arg| arg.to_str().unwrap().to_string())
+            .collect();
+        assert!(!args.iter().any(|arg| arg.contains("sysroot")));
+        assert!(!args.iter().any(|arg| arg.contains("gcc-toolchain")));
+        assert_eq!(check_remote_args(&args, false), Ok(args));
+    }
+
+    #[test]
+    fn test_precompiled_header() {
+        let args: Vec<String> = "-x c++-header -pipe -Wall -O2 -DUNICODE \
+            -I/opt/UnrealEngine/Engine/Source/Runtime/Core/Public -MD -MFCorePrivatePCH.h.d \
+            -std=c++20 -o CorePrivatePCH.h.pch CorePrivatePCH.h"
+            .split_whitespace()
+            .map(str::to_string)
+            .collect();
+        let tasks =
+            create_tasks(CommandInfo::simple(PathBuf::from("clang++")), args, false).unwrap();
+        assert_eq!(tasks.len(), 1);
+        let pch = tasks[0].shared.pch_usage.get_out_abs().unwrap();
+        assert!(pch.ends_with("CorePrivatePCH.h.pch"));
+        assert_eq!(&tasks[0].output_object, pch);
+
+        let step = ClangToolchain::new(PathBuf::from("clang++"))
+            .create_compile_step(&tasks[0], CompilerOutput::Vec(Vec::new()))
+            .unwrap();
+        // Precompiled header is the only output
+        assert!(step.output_object.is_none());
+        assert_eq!(step.output(), Some(pch.as_path()));
+        // Preprocessed data is compiled without inherited environment
+        assert!(step.clear_env);
+        let args: Vec<String> = step
+            .args
+            .iter()
+            .map(|arg| arg.to_str().unwrap().to_string())
+            .collect();
+        assert_eq!(args[..2], ["-x", "c++-header"]);
+        assert!(!args.iter().any(|arg| arg.starts_with("-I") || arg == "-MD"));
+        assert_eq!(check_remote_args(&args, true), Ok(args));
+    }
+
+    // Precompiled header generated from preprocessed data, the same way as on builder,
+    // is accepted by local compilation with original header.
+    #[test]
+    fn test_remote_precompiled_header() {
+        if !clang_available() {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("common.h"),
+            "#pragma once\ninline int answer() { return ANSWER; }\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("pch.h"),
+            "#include \"common.h\"\n#define SQUARE(x) ((x) * (x))\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("main.cpp"),
+            "int main() { return SQUARE(answer()) == 1764 ? 0 : 1; }\n",
+        )
+        .unwrap();
+        let state = SharedState::new(&Config {
+            cache: dir.path().join("cache"),
+            ..Config::default()
+        })
+        .unwrap();
+        let command = CommandInfo {
+            current_dir: Some(dir.path().to_path_buf()),
+            ..CommandInfo::simple(PathBuf::from("clang++"))
+        };
+        let toolchain = ClangToolchain::new(PathBuf::from("clang++"));
+        let task = create_tasks(
+            command,
+            "-x c++-header -DANSWER=42 pch.h -o pch.h.pch"
+                .split_whitespace()
+                .map(str::to_string)
+                .collect(),
+            false,
+        )
+        .unwrap()
+        .remove(0);
+        let PreprocessResult::Success(preprocessed) =
+            toolchain.run_preprocess(&state, &task).unwrap()
+        else {
+            panic!("Preprocessing failed");
+        };
+        let step = toolchain.create_compile_step(&task, preprocessed).unwrap();
+
+        // Builder gets only compile step arguments and preprocessed data
+        let args: Vec<String> = step
+            .args
+            .iter()
+            .map(|arg| arg.to_str().unwrap().to_string())
+            .collect();
+        let args = check_remote_args(&args, true).unwrap();
+        let received = dir.path().join("received.i");
+        let Preprocessed(preprocessed) = &step.input else {
+            panic!("Unexpected compile step input");
+        };
+        preprocessed
+            .copy(&mut File::create(&received).unwrap())
+            .unwrap();
+        let pch = dir.path().join("pch.h.pch");
+        let output = toolchain
+            .run_compile(
+                &state,
+                CompileStep {
+                    args: args.iter().map(OsString::from).collect(),
+                    output_object: None,
+                    pch_usage: PCHUsage::Out(PCHArgs {
+                        path: pch.clone(),
+                        path_abs: pch.clone(),
+                        marker: None,
+                    }),
+                    input: PreprocessedFile(received.clone()),
+                    run_second_cpp: false,
+                    clear_env: true,
+                },
+            )
+            .unwrap();
+        assert!(
+            output.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        // Builder removes received data after compilation
+        fs::remove_file(&received).unwrap();
+
+        let output = Command::new("clang++")
+            .current_dir(dir.path())
+            .args(["-DANSWER=42", "-include-pch", "pch.h.pch", "-c", "main.cpp"])
+            .args(["-o", "main.o"])
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
     #[test]
     fn test_ubuntu_14_04_clang_3_5() {
         assert_eq!(
@@ -0,0 +1,77 @@
+use std::path::PathBuf;
+
+// Parse prerequisites from Makefile-style dependency file (as produced by `-MD`/`-MF`).
+#[must_use]
+pub fn parse_prerequisites(data: &str) -> Vec<PathBuf> {
+    let data = data.replace("\\\r\n", " ").replace("\\\n", " ");
+    let mut result = Vec::new();
+    for line in data.lines() {
+        if let Some(prerequisites) = split_rule(line) {
+            result.extend(split_paths(prerequisites).into_iter().map(PathBuf::from));
+        }
+    }
+    result
+}
+
+// Returns rule part after target separator. Colon of Windows drive letter is not a separator.
+fn split_rule(line: &str) -> Option<&str> {
+    let bytes = line.as_bytes();
+    for (index, c) in bytes.iter().enumerate() {
+        if *c == b':' && bytes.get(index + 1).is_none_or(u8::is_ascii_whitespace) {
+            return Some(&line[index + 1..]);
+        }
+    }
+    None
+}
+
+fn split_paths(line: &str) -> Vec<String> {
+    let mut result = Vec::new();
+    let mut path = String::new();
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek().is_some_and(|n| *n == ' ' || *n == '#') => {
+                path.push(chars.next().unwrap());
+            }
+            '$' if chars.peek() == Some(&'$') => {
+                path.push(chars.next().unwrap());
+            }
+            c if c.is_whitespace() => {
+                if !path.is_empty() {
+                    result.push(std::mem::take(&mut path));
+                }
+            }
+            c => path.push(c),
+        }
+    }
+    if !path.is_empty() {
+        result.push(path);
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use crate::clang::depfile::parse_prerequisites;
+
+    #[test]
+    fn test_parse_prerequisites() {
+        assert_eq!(
+            parse_prerequisites(
+                "/tmp/out.o: /tmp/src.cpp /usr/include/a.h \\\n  /tmp/with\\ space.h C:\\foo\\$$b.h\n/usr/include/a.h:\n"
+            ),
+            vec![
+                PathBuf::from("/tmp/src.cpp"),
+                PathBuf::from("/usr/include/a.h"),
+                PathBuf::from("/tmp/with space.h"),
+                PathBuf::from("C:\\foo\\$b.h"),
+            ]
+        );
+        assert_eq!(
+            parse_prerequisites("C:\\out.o: C:\\src.cpp\r\n"),
+            vec![PathBuf::from("C:\\src.cpp")]
+        );
+    }
+}
@@ -1,6 +1,6 @@
 use crate::compiler::{
-    Arg, CommandInfo, CompilationArgs, CompilationTask, InputKind, OutputKind, PCHUsage, ParamForm,
-    Scope,
+    check_remote_args as check_remote_args_parsed, Arg, CommandInfo, CompilationArgs,
+    CompilationTask, DepsFormat, InputKind, OutputKind, PCHArgs, PCHUsage, ParamForm, Scope,
 };
 use crate::utils::{expand_response_files, find_param, ParamValue};
 use std::path::PathBuf;
@@ -19,8 +19,12 @@ pub fn create_tasks(
         return Ok(Vec::new());
     }
 
-    if !expanded_args.iter().any(|v| matches!(v as &str, "-c")) {
-        // Support only compilation steps
+    if !expanded_args.iter().any(|v| matches!(v as &str, "-c"))
+        && !expanded_args
+            .windows(2)
+            .any(|v| v[0] == "-x" && is_header_language(&v[1]))
+    {
+        // Support only compilation steps and precompiled header generation
         return Ok(Vec::new());
     }
 
@@ -90,15 +94,25 @@ pub fn create_tasks(
     }
     .map_or(Ok(None), |v| v.map(Some))?;
 
-    let deps_file = parsed_args
+    // Dependency file is written only if it is requested by `-MD`/`-MMD`
+    let deps_requested = parsed_args
         .iter()
-        .find_map(|arg| match arg {
-            Arg::Output { kind, file, .. } if *kind == OutputKind::Deps => {
-                Some(command.absolutize(file))
-            }
-            _ => None,
-        })
-        .map_or(Ok(None), |v| v.map(Some))?;
+        .any(|arg| matches!(arg, Arg::Flag { name, .. } if name == "MD" || name == "MMD"));
+    let deps_file = if deps_requested {
+        parsed_args
+            .iter()
+            .find_map(|arg| match arg {
+                Arg::Output { kind, file, .. } if *kind == OutputKind::Deps => {
+                    Some(command.absolutize(file))
+                }
+                _ => None,
+            })
+            .map_or(Ok(None), |v| v.map(Some))?
+            // `-MD` without `-MF` writes dependencies next to output object
+            .or_else(|| output_object.as_ref().map(|path| path.with_extension("d")))
+    } else {
+        None
+    };
 
     // Language
     let language: Option<String> = match find_param(&parsed_args, |arg: &Arg| -> Option<String> {
@@ -110,32 +124,52 @@ pub fn create_tasks(
         }
     }) {
         ParamValue::None => None,
-        ParamValue::Single(v) => {
-            match &v[..] {
-                "c" | "c++" | "objective-c++" => Some(v.to_string()),
-                "c-header" | "c++-header" | "objective-c++-header" => {
-                    // Precompiled headers must build locally
-                    return Ok(Vec::new());
-                }
-                _ => {
-                    return Err(crate::Error::from(format!(
-                        "Unknown source language type: {v}"
-                    )));
-                }
+        ParamValue::Single(v) => match &v[..] {
+            "c" | "c++" | "objective-c++" => Some(v.to_string()),
+            v if is_header_language(v) => Some(v.to_string()),
+            _ => {
+                return Err(crate::Error::from(format!(
+                    "Unknown source language type: {v}"
+                )));
             }
-        }
+        },
         ParamValue::Many(v) => {
             return Err(crate::Error::from(format!(
                 "Found too many output object files: {v:?}"
             )));
         }
     };
+    // Precompiled header is the only output of header compilation
+    let pch_usage = match &language {
+        Some(language) if is_header_language(language) => {
+            if input_sources.len() > 1 {
+                return Err(crate::Error::from(
+                    "Cannot generate precompiled header from multiple files",
+                ));
+            }
+            let path = match &output_object {
+                Some(path) => path.clone(),
+                None => {
+                    let mut path = input_sources[0].clone().into_os_string();
+                    path.push(".gch");
+                    command.absolutize(&PathBuf::from(path))?
+                }
+            };
+            PCHUsage::Out(PCHArgs {
+                path: path.clone(),
+                path_abs: path,
+                marker: None,
+            })
+        }
+        _ => PCHUsage::None,
+    };
+    let output_object = output_object.or_else(|| pch_usage.get_out_abs().cloned());
     let shared = Arc::new(CompilationArgs {
         command,
         args: parsed_args,
-        // No PCH support for clang for now
-        pch_usage: PCHUsage::None,
+        pch_usage,
         deps_file,
+        deps_format: DepsFormat::Make,
         run_second_cpp,
     });
     input_sources
@@ -173,6 +207,104 @@ pub fn create_tasks(
         .collect()
 }
 
+fn is_header_language(language: &str) -> bool {
+    matches!(language, "c-header" | "c++-header" | "objective-c++-header")
+}
+
+// Arguments, which allow to load code or read files on builder.
+const DENIED_REMOTE_ARGS: &[&str] = &[
+    "fplugin",
+    "fpass-plugin",
+    "fprofile-use",
+    "fprofile-instr-use",
+    "fprofile-sample-use",
+    "fsanitize-blacklist",
+    "fsanitize-ignorelist",
+    "fcrash-diagnostics",
+    // Forwarded by driver to preprocessor, assembler and linker as is
+    "Wp,",
+    "Wa,",
+    "Wl,",
+];
+
+// Options, which remap paths written into debug info, macros and coverage data.
+const PATH_MAP_REMOTE_ARGS: &[&str] = &[
+    "fcoverage-prefix-map",
+    "fdebug-prefix-map",
+    "ffile-prefix-map",
+    "fmacro-prefix-map",
+    "fprofile-prefix-map",
+];
+
+// Options with values, which don't reference files, for `-f` and `-m` parameters.
+const VALUED_REMOTE_ARGS: &[(&str, &[&str])] = &[
+    (
+        "f",
+        &[
+            "bracket-depth",
+            "cf-protection",
+            "constexpr-depth",
+            "constexpr-steps",
+            "debug-default-version",
+            "denormal-fp-math",
+            "diagnostics-color",
+            "diagnostics-format",
+            "fp-contract",
+            "fp-exception-behavior",
+            "fp-model",
+            "lto",
+            "macro-backtrace-limit",
+            "message-length",
+            "ms-compatibility-version",
+            "ms-runtime-lib",
+            "msc-version",
+            "no-sanitize",
+            "no-sanitize-recover",
+            "sanitize",
+            "sanitize-address-use-after-return",
+            "sanitize-recover",
+            "sanitize-trap",
+            "template-backtrace-limit",
+            "template-depth",
+            "trivial-auto-var-init",
+            "visibility",
+            "zero-call-used-regs",
+        ],
+    ),
+    (
+        "m",
+        &[
+            "abi",
+            "arch",
+            "branch-protection",
+            "cmodel",
+            "cpu",
+            "float-abi",
+            "fpu",
+            "function-return",
+            "indirect-branch",
+            "ios-version-min",
+            "macosx-version-min",
+            "prefer-vector-width",
+            "stack-alignment",
+            "stack-protector-guard",
+            "tls-dialect",
+            "tune",
+        ],
+    ),
+];
+
+pub fn check_remote_args(args: &[String], output_precompiled: bool) -> Result<Vec<String>, String> {
+    check_remote_args_parsed(
+        args,
+        parse_argument,
+        DENIED_REMOTE_ARGS,
+        VALUED_REMOTE_ARGS,
+        PATH_MAP_REMOTE_ARGS,
+        output_precompiled,
+    )
+}
+
 fn parse_arguments(args: Vec<String>) -> Result<Vec<Arg>, String> {
     let mut result: Vec<Arg> = Vec::new();
     let mut errors: Vec<String> = Vec::new();
@@ -221,13 +353,14 @@ static DASH_DASH_PARAMS: &[CompilerArgument] = &[
         name: "driver-mode",
         value_type: COMBINED,
     },
+    // Header and library search roots aren't needed to compile preprocessed data
     CompilerArgument {
-        scope: Scope::Shared,
+        scope: Scope::Preprocessor,
         name: "gcc-toolchain",
         value_type: COMBINED,
     },
     CompilerArgument {
-        scope: Scope::Shared,
+        scope: Scope::Preprocessor,
         name: "sysroot",
         value_type: NORMAL,
     },
@@ -351,6 +484,11 @@ static DASH_PARAMS: &[CompilerArgument] = &[
         name: "MD",
         value_type: NONE,
     },
+    CompilerArgument {
+        scope: Scope::Preprocessor,
+        name: "MMD",
+        value_type: NONE,
+    },
     CompilerArgument {
         scope: Scope::Preprocessor,
         name: "MF",
@@ -533,7 +671,7 @@ fn test_parse_argument_precompile() {
             Arg::flag(Scope::Preprocessor, "-", "MD"),
             Arg::flag(Scope::Shared, "-", "nostdinc++"),
             Arg::param_ext(
-                Scope::Shared,
+                Scope::Preprocessor,
                 "--",
                 "gcc-toolchain",
                 "/bla/bla",
@@ -636,3 +774,74 @@ fn test_parse_argument_compile() {
         ]
     )
 }
+
+#[test]
+fn test_check_remote_args() {
+    let split = |args: &str| -> Vec<String> { args.split(' ').map(|x| x.to_string()).collect() };
+    let check = |args: &str| check_remote_args(&split(args), false);
+    assert!(
+        check("-x c++ -Wall -O2 -fno-rtti -DPLATFORM_HEADER=\"Linux/Platform.h\" -std=c++11")
+            .is_ok()
+    );
+    assert!(check("-x c++ -fplugin=evil.so").is_err());
+    assert!(check("-x c++ -Xclang -load -Xclang evil.so").is_err());
+    assert!(check("-x c++ -o /tmp/evil").is_err());
+    assert!(check("-x c++ -I /usr/include").is_err());
+    // Search roots are dropped from compile step, so they are never sent to builder
+    assert!(check("-x c++ --gcc-toolchain=/opt/gcc").is_err());
+    assert!(check("-x c++ --sysroot=/opt/sysroot").is_err());
+    assert!(check("-x c++ main.cpp").is_err());
+    // Options with values are allowed only from known list
+    assert!(check("-x c++ -fvisibility=hidden -march=x86-64-v3 -fsanitize=address").is_ok());
+    assert!(check("-x c++ -fprofile-generate=x").is_err());
+    assert!(check("-x c++ -fsanitize-coverage-allowlist=x").is_err());
+    assert!(check("-x c++ -fmodule-file=x").is_err());
+    assert!(check("-x c++ -frandomize-layout-seed-file=x").is_err());
+    assert!(check("-x c++ -Xclang -fmodule-file=x").is_err());
+    assert!(check("-x c++ -mstack-protector-guard-symbol=x").is_err());
+    assert!(check("-x c++ -Wno-unused -Wframe-larger-than=4096").is_ok());
+    assert!(check("-x c++ -Wp,-load,evil.so").is_err());
+    assert!(check("-x c++ -Wp,-MD,evil.d").is_err());
+    assert!(check("-x c++ -Wa,-aln=evil.lst").is_err());
+    assert!(check("-x c++ -Wl,-plugin,evil.so").is_err());
+    // Path remapping doesn't access files
+    assert!(check(
+        "-x c++ -ffile-prefix-map=/home/user/src=. -fdebug-prefix-map=/home/user=/ \
+         -fmacro-prefix-map=C:\\src=src"
+    )
+    .is_ok());
+    assert!(check("-x c++ -ffile-prefix-map-evil=/tmp").is_err());
+    assert!(check("-x c++ -fprofile-use=/tmp/default.profdata").is_err());
+}
+
+#[test]
+fn test_deps_file() {
+    let dir = std::env::temp_dir();
+    let command = CommandInfo {
+        current_dir: Some(dir.clone()),
+        ..CommandInfo::simple(PathBuf::from("clang"))
+    };
+    let deps_file = |args: &str| {
+        create_tasks(
+            command.clone(),
+            args.split(' ').map(str::to_string).collect(),
+            false,
+        )
+        .unwrap()
+        .remove(0)
+        .shared
+        .deps_file
+        .clone()
+    };
+    assert_eq!(deps_file("-c main.cpp -o main.o"), None);
+    // `-MF` alone doesn't request dependency file
+    assert_eq!(deps_file("-c main.cpp -o main.o -MF main.dep"), None);
+    assert_eq!(
+        deps_file("-c main.cpp -o main.o -MMD -MF main.dep"),
+        Some(dir.join("main.dep"))
+    );
+    assert_eq!(
+        deps_file("-c main.cpp -o main.o -MD"),
+        Some(dir.join("main.d"))
+    );
+}
@@ -0,0 +1,328 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use reqwest::blocking::RequestBuilder;
+use sha2::{Digest, Sha256};
+use url::Position;
+
+use crate::config::Config;
+
+pub const TIMESTAMP_HEADER: &str = "X-Octobuild-Timestamp";
+pub const SIGNATURE_HEADER: &str = "X-Octobuild-Signature";
+// Declared size of uncompressed request body.
+pub const BODY_SIZE_HEADER: &str = "X-Octobuild-Body-Size";
+// Signature of request headers, checked before reading request body.
+pub const HEADER_SIGNATURE_HEADER: &str = "X-Octobuild-Header-Signature";
+
+// Max allowed clock difference between cluster hosts.
+const MAX_CLOCK_SKEW_SECS: u64 = 300;
+
+/// Shared-secret request signing between client, builder and coordinator.
+///
+/// Signature is HMAC-SHA256 over request method, path with query string, timestamp and SHA-256 of request body.
+/// Header signature covers the same fields with declared body size instead of body digest, so forged requests
+/// are rejected before reading body.
+///
+/// There is no nonce: captured request can be replayed while its timestamp is in allowed range.
+pub struct ClusterAuth {
+    key: Vec<u8>,
+}
+
+impl ClusterAuth {
+    #[must_use]
+    pub fn new(config: &Config) -> Option<Self> {
+        config
+            .cluster_token
+            .as_ref()
+            .filter(|token| !token.is_empty())
+            .map(|token| ClusterAuth::from_token(token))
+    }
+
+    #[must_use]
+    pub fn from_token(token: &str) -> Self {
+        ClusterAuth {
+            key: token.as_bytes().to_vec(),
+        }
+    }
+
+    fn mac(&self, method: &str, path: &str, timestamp: u64, body_digest: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).unwrap();
+        mac.update(format!("{method}\n{path}\n{timestamp}\n{body_digest}").as_bytes());
+        mac
+    }
+
+    fn header_mac(&self, method: &str, path: &str, timestamp: u64, body_size: u64) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).unwrap();
+        mac.update(format!("header\n{method}\n{path}\n{timestamp}\n{body_size}").as_bytes());
+        mac
+    }
+
+    #[must_use]
+    pub fn sign(&self, method: &str, path: &str, timestamp: u64, body_digest: &str) -> String {
+        hex::encode(
+            self.mac(method, path, timestamp, body_digest)
+                .finalize()
+                .into_bytes(),
+        )
+    }
+
+    #[must_use]
+    pub fn sign_header(&self, method: &str, path: &str, timestamp: u64, body_size: u64) -> String {
+        hex::encode(
+            self.header_mac(method, path, timestamp, body_size)
+                .finalize()
+                .into_bytes(),
+        )
+    }
+
+    // Add signature headers to request.
+    pub fn apply(
+        &self,
+        request: RequestBuilder,
+        method: &str,
+        url: &reqwest::Url,
+        body_size: u64,
+        body_digest: &str,
+    ) -> RequestBuilder {
+        let timestamp = unix_time();
+        let path = &url[Position::BeforePath..Position::AfterQuery];
+        request
+            .header(TIMESTAMP_HEADER, timestamp)
+            .header(BODY_SIZE_HEADER, body_size)
+            .header(
+                HEADER_SIGNATURE_HEADER,
+                self.sign_header(method, path, timestamp, body_size),
+            )
+            .header(
+                SIGNATURE_HEADER,
+                self.sign(method, path, timestamp, body_digest),
+            )
+    }
+
+    pub fn verify(
+        &self,
+        method: &str,
+        path: &str,
+        timestamp: Option<&str>,
+        signature: Option<&str>,
+        body_digest: &str,
+    ) -> Result<(), String> {
+        let (timestamp, signature) = parse_signature(timestamp, signature)?;
+        self.mac(method, path, timestamp, body_digest)
+            .verify_slice(&signature)
+            .map_err(|_| "Invalid request signature".to_string())
+    }
+
+    pub fn verify_header(
+        &self,
+        method: &str,
+        path: &str,
+        timestamp: Option<&str>,
+        signature: Option<&str>,
+        body_size: Option<&str>,
+    ) -> Result<u64, String> {
+        let (timestamp, signature) = parse_signature(timestamp, signature)?;
+        let body_size: u64 = body_size
+            .and_then(|v| v.trim().parse().ok())
+            .ok_or("Request is not signed")?;
+        self.header_mac(method, path, timestamp, body_size)
+            .verify_slice(&signature)
+            .map_err(|_| "Invalid request signature".to_string())?;
+        Ok(body_size)
+    }
+
+    // Verify header signature of received request before reading its body, body digest is checked later.
+    // Returns declared body size or error response.
+    pub fn precheck(&self, request: &rouille::Request) -> Result<u64, rouille::Response> {
+        self.verify_header(
+            request.method(),
+            request.raw_url(),
+            request.header(TIMESTAMP_HEADER),
+            request.header(HEADER_SIGNATURE_HEADER),
+            request.header(BODY_SIZE_HEADER),
+        )
+        .map_err(|message| rouille::Response::text(message).with_status_code(401))
+    }
+
+    // Verify signature of received request, returns error response on failure.
+    #[must_use]
+    pub fn check(
+        &self,
+        request: &rouille::Request,
+        body_digest: &str,
+    ) -> Option<rouille::Response> {
+        self.verify(
+            request.method(),
+            request.raw_url(),
+            request.header(TIMESTAMP_HEADER),
+            request.header(SIGNATURE_HEADER),
+            body_digest,
+        )
+        .err()
+        .map(|message| rouille::Response::text(message).with_status_code(401))
+    }
+}
+
+fn parse_signature(
+    timestamp: Option<&str>,
+    signature: Option<&str>,
+) -> Result<(u64, Vec<u8>), String> {
+    let timestamp: u64 = timestamp
+        .and_then(|v| v.trim().parse().ok())
+        .ok_or("Request is not signed")?;
+    let signature = signature
+        .and_then(|v| hex::decode(v.trim()).ok())
+        .ok_or("Request is not signed")?;
+    if unix_time().abs_diff(timestamp) > MAX_CLOCK_SKEW_SECS {
+        return Err("Request timestamp is out of allowed range".to_string());
+    }
+    Ok((timestamp, signature))
+}
+
+#[must_use]
+pub fn body_digest(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+pub(crate) fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |v| v.as_secs())
+}
+
+#[cfg(test)]
+mod test {
+    use rouille::Request;
+
+    use crate::cluster::auth::{body_digest, unix_time, ClusterAuth};
+
+    #[test]
+    fn test_sign_verify() {
+        let auth = ClusterAuth::from_token("secret");
+        let digest = body_digest(b"payload");
+        let now = unix_time();
+        let signature = auth.sign("POST", "/rpc/v1/builder/task", now, &digest);
+        let timestamp = now.to_string();
+        assert!(auth
+            .verify(
+                "POST",
+                "/rpc/v1/builder/task",
+                Some(&timestamp),
+                Some(&signature),
+                &digest
+            )
+            .is_ok());
+        // Another body
+        assert!(auth
+            .verify(
+                "POST",
+                "/rpc/v1/builder/task",
+                Some(&timestamp),
+                Some(&signature),
+                &body_digest(b"other")
+            )
+            .is_err());
+        // Another token
+        assert!(ClusterAuth::from_token("other")
+            .verify(
+                "POST",
+                "/rpc/v1/builder/task",
+                Some(&timestamp),
+                Some(&signature),
+                &digest
+            )
+            .is_err());
+        // Not signed
+        assert!(auth
+            .verify("POST", "/rpc/v1/builder/task", None, None, &digest)
+            .is_err());
+        // Expired
+        let old = now - 3600;
+        let signature = auth.sign("POST", "/rpc/v1/builder/task", old, &digest);
+        assert!(auth
+            .verify(
+                "POST",
+                "/rpc/v1/builder/task",
+                Some(&old.to_string()),
+                Some(&signature),
+                &digest
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_signed_query() {
+        let auth = ClusterAuth::from_token("secret");
+        let url =
+            reqwest::Url::parse("http://127.0.0.1:3000/rpc/v1/builder/list?labels=linux").unwrap();
+        let digest = body_digest(&[]);
+        let signed = auth
+            .apply(
+                reqwest::blocking::Client::new().get(url.clone()),
+                "GET",
+                &url,
+                0,
+                &digest,
+            )
+            .build()
+            .unwrap();
+        let request = |url: &str| {
+            let headers = signed
+                .headers()
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_str().unwrap().to_string()))
+                .collect();
+            Request::fake_http("GET", url, headers, Vec::new())
+        };
+        assert_eq!(
+            auth.precheck(&request("/rpc/v1/builder/list?labels=linux"))
+                .ok(),
+            Some(0)
+        );
+        assert!(auth
+            .check(&request("/rpc/v1/builder/list?labels=linux"), &digest)
+            .is_none());
+        // Query string can't be changed without signature
+        assert!(auth
+            .check(&request("/rpc/v1/builder/list?labels=windows"), &digest)
+            .is_some());
+        assert!(auth
+            .precheck(&request("/rpc/v1/builder/list?labels=windows"))
+            .is_err());
+        assert!(auth
+            .precheck(&Request::fake_http(
+                "GET",
+                "/rpc/v1/builder/list",
+                Vec::new(),
+                Vec::new()
+            ))
+            .is_err());
+    }
+
+    #[test]
+    fn test_header_signature() {
+        let auth = ClusterAuth::from_token("secret");
+        let now = unix_time();
+        let timestamp = now.to_string();
+        let signature = auth.sign_header("POST", "/rpc/v1/builder/task", now, 1024);
+        let verify = |auth: &ClusterAuth, signature: &str, size: &str| {
+            auth.verify_header(
+                "POST",
+                "/rpc/v1/builder/task",
+                Some(&timestamp),
+                Some(signature),
+                Some(size),
+            )
+        };
+        assert_eq!(verify(&auth, &signature, "1024"), Ok(1024));
+        // Declared size can't be changed without signature
+        assert!(verify(&auth, &signature, "1").is_err());
+        // Forged signature
+        assert!(verify(&ClusterAuth::from_token("other"), &signature, "1024").is_err());
+        let forged = auth.sign_header("POST", "/rpc/v1/builder/task", now, 1);
+        assert!(verify(&auth, &forged, "1024").is_err());
+        // Body signature is not accepted as header signature
+        let body = auth.sign("POST", "/rpc/v1/builder/task", now, "1024");
+        assert!(verify(&auth, &body, "1024").is_err());
+    }
+}
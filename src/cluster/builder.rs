@@ -1,26 +1,113 @@
 use bincode::{Decode, Encode};
+use sha2::{Digest, Sha256};
 
 use crate::compiler::OutputInfo;
 
+// Max size of encoded task request, which precedes preprocessed data in body.
+pub const MAX_REQUEST_SIZE: usize = 1 << 20;
+// Max size of preprocessed data accepted by builders.
+pub const MAX_PREPROCESSED_SIZE: u64 = 1 << 30;
+// Max size of precompiled header uploaded to builders.
+pub const MAX_PRECOMPILED_SIZE: u64 = 1 << 31;
+
 #[derive(Decode, Encode, Debug)]
 pub struct CompileRequest {
     pub toolchain: String,
     pub args: Vec<String>,
-    pub preprocessed_data: Vec<u8>,
+    // Size of preprocessed data, which follows request in body
+    pub preprocessed_size: u64,
     pub precompiled_hash: Option<String>,
+    // Client expects precompiled header generated by this task
+    pub precompiled_output: bool,
+    // Client expects object file (clang precompiled header tasks produce only the header)
+    pub object_output: bool,
+}
+
+impl CompileRequest {
+    // Key of compilation result in builder cache.
+    #[must_use]
+    pub fn cache_key(&self, preprocessed_hash: &str) -> String {
+        let mut hasher = Sha256::new();
+        let mut hash_bytes = |bytes: &[u8]| {
+            hasher.update((bytes.len() as u64).to_le_bytes());
+            hasher.update(bytes);
+        };
+        hash_bytes(b"builder");
+        hash_bytes(self.toolchain.as_bytes());
+        hash_bytes(&(self.args.len() as u64).to_le_bytes());
+        for arg in &self.args {
+            hash_bytes(arg.as_bytes());
+        }
+        hash_bytes(preprocessed_hash.as_bytes());
+        match &self.precompiled_hash {
+            Some(hash) => {
+                hash_bytes(&[1]);
+                hash_bytes(hash.as_bytes());
+            }
+            None => hash_bytes(&[0]),
+        }
+        hash_bytes(&[u8::from(self.precompiled_output)]);
+        hash_bytes(&[u8::from(self.object_output)]);
+        hex::encode(hasher.finalize())
+    }
+}
+
+// Kind of file produced by remote compilation. Dependency files are written by local
+// preprocessing and diagnostics are returned as compiler output, so they aren't transferred.
+#[derive(Decode, Encode, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFileKind {
+    Object,
+    Precompiled,
+}
+
+#[derive(Decode, Encode, Debug)]
+pub struct OutputFile {
+    pub kind: OutputFileKind,
+    // Size of file content, which follows response in body
+    pub size: u64,
 }
 
 #[derive(Decode, Encode, Debug)]
 pub enum CompileResponse {
-    Success(OutputInfo),
+    // Compiler status and output with content of produced files
+    Success {
+        output: OutputInfo,
+        files: Vec<OutputFile>,
+    },
     Err(String),
+    // Builder doesn't have requested toolchain, client should refresh builder list
+    UnknownToolchain(String),
 }
 
-impl From<crate::Result<OutputInfo>> for CompileResponse {
-    fn from(result: crate::Result<OutputInfo>) -> Self {
-        match result {
-            Ok(output) => CompileResponse::Success(output),
-            Err(v) => CompileResponse::Err(v.to_string()),
+#[cfg(test)]
+mod test {
+    use crate::cluster::builder::CompileRequest;
+
+    const HASH: &str = "d2a84f4b8b650937ec8f73cd8be2c74add5a911ba64df27458ed8229da804a26";
+
+    fn request(args: &[&str], precompiled_hash: Option<&str>) -> CompileRequest {
+        CompileRequest {
+            toolchain: "clang-18".to_string(),
+            args: args.iter().map(ToString::to_string).collect(),
+            preprocessed_size: 24,
+            precompiled_hash: precompiled_hash.map(ToString::to_string),
+            precompiled_output: false,
+            object_output: true,
         }
     }
+
+    #[test]
+    fn test_cache_key() {
+        let key = request(&["-O2"], None).cache_key(HASH);
+        assert_eq!(key, request(&["-O2"], None).cache_key(HASH));
+        assert_ne!(key, request(&["-O3"], None).cache_key(HASH));
+        assert_ne!(key, request(&["-O", "2"], None).cache_key(HASH));
+        assert_ne!(key, request(&["-O2"], Some("")).cache_key(HASH));
+        assert_ne!(key, request(&["-O2"], None).cache_key(""));
+        let mut output = request(&["-O2"], None);
+        output.precompiled_output = true;
+        assert_ne!(key, output.cache_key(HASH));
+        output.object_output = false;
+        assert_ne!(key, output.cache_key(HASH));
+    }
 }
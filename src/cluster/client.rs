@@ -1,25 +1,51 @@
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::fs::File;
-use std::io::{Error, ErrorKind, Write};
+use std::io::{Error, ErrorKind, Read};
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::str::FromStr;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
 use std::time::{Duration, Instant};
 
 use log::{trace, warn};
-use rand::seq::IteratorRandom;
-use reqwest::blocking::Client;
-use reqwest::StatusCode;
+use rand::seq::SliceRandom;
+use reqwest::blocking::{Client, RequestBuilder};
+use reqwest::header::{HeaderName, ACCEPT_ENCODING, CONTENT_ENCODING};
+use reqwest::{Method, StatusCode};
+use sha2::{Digest, Sha256};
 
 use crate::cache::FileHasher;
-use crate::cluster::builder::{CompileRequest, CompileResponse};
-use crate::cluster::common::{BuilderInfo, RPC_BUILDER_LIST, RPC_BUILDER_TASK, RPC_BUILDER_UPLOAD};
+use crate::cluster::auth::{body_digest, ClusterAuth};
+use crate::cluster::builder::{
+    CompileRequest, CompileResponse, OutputFile, OutputFileKind, MAX_PRECOMPILED_SIZE,
+    MAX_PREPROCESSED_SIZE, MAX_REQUEST_SIZE,
+};
+use crate::cluster::common::{
+    has_encoding, http_client, read_body, write_body, BuilderFailure, BuilderInfo, ENCODING_LZ4,
+    LABELS_PARAM, PROTOCOL_VERSION, RPC_BUILDER_FAILURE, RPC_BUILDER_LIST, RPC_BUILDER_TASK,
+    RPC_BUILDER_UPLOAD,
+};
+use crate::cluster::discovery::DiscoveryListener;
 use crate::compiler::CompileInput::Preprocessed;
 use crate::compiler::{
     CommandInfo, CompilationTask, CompileStep, Compiler, CompilerOutput, OutputInfo,
     PreprocessResult, SharedState, Toolchain,
 };
+use crate::config::Config;
+use crate::events::Event;
+use crate::io::pipe::pipe;
+
+// Max count of builders to try before compiling locally.
+const REMOTE_ATTEMPTS: usize = 3;
+// Don't start new remote attempts after this time.
+const REMOTE_DEADLINE: Duration = Duration::from_secs(30);
+// Blacklist period after first builder failure, doubles on each consecutive failure.
+const FAILURE_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const FAILURE_BACKOFF_MAX: Duration = Duration::from_secs(60);
+// Chunks of preprocessed data buffered between request producer thread and HTTP client.
+const REQUEST_PIPE_CAPACITY: usize = 16;
 
 pub struct RemoteCompiler<C: Compiler> {
     shared: Arc<RemoteShared>,
@@ -34,8 +60,39 @@ struct RemoteSharedMut {
 
 struct RemoteShared {
     mutable: RwLock<RemoteSharedMut>,
+    // Count of requests in progress by builder endpoint
+    pending: Mutex<HashMap<String, usize>>,
+    // Health of failed or fully loaded builders by endpoint
+    health: Mutex<HashMap<String, BuilderHealth>>,
+    // Builder endpoints known to accept lz4-compressed requests
+    lz4_builders: RwLock<HashSet<String>>,
     base_url: Option<reqwest::Url>,
+    // Builder announcements listener, used when coordinator isn't configured
+    discovery: Option<DiscoveryListener>,
     client: Client,
+    auth: Option<ClusterAuth>,
+    // Labels required from builders
+    selector: Vec<String>,
+    // Reasons of local fallback already reported to user
+    fallback_warnings: Mutex<HashSet<String>>,
+}
+
+#[derive(Default)]
+struct BuilderHealth {
+    // Count of consecutive failures
+    failures: u32,
+    // Don't send tasks to builder until this time
+    blocked_until: Option<Instant>,
+}
+
+impl BuilderHealth {
+    fn is_blocked(&self, now: Instant) -> bool {
+        self.blocked_until.is_some_and(|until| until > now)
+    }
+
+    fn block(&mut self, until: Instant) {
+        self.blocked_until = Some(self.blocked_until.map_or(until, |v| v.max(until)));
+    }
 }
 
 struct RemoteToolchain {
@@ -44,34 +101,95 @@ struct RemoteToolchain {
 }
 
 impl<C: Compiler> RemoteCompiler<C> {
-    pub fn new(base_url: &Option<reqwest::Url>, compiler: C) -> Self {
-        RemoteCompiler {
+    pub fn new(config: &Config, compiler: C) -> crate::Result<Self> {
+        Ok(RemoteCompiler {
             shared: Arc::new(RemoteShared {
                 mutable: RwLock::new(RemoteSharedMut {
                     cooldown: Instant::now(),
                     builders: Arc::new(Vec::new()),
                 }),
-                base_url: base_url.as_ref().cloned(),
-                client: Client::new(),
+                pending: Mutex::new(HashMap::new()),
+                health: Mutex::new(HashMap::new()),
+                lz4_builders: RwLock::new(HashSet::new()),
+                base_url: config.coordinator.clone(),
+                discovery: discovery_listener(config),
+                client: http_client(config)?,
+                auth: ClusterAuth::new(config),
+                selector: config.builder_selector.clone(),
+                fallback_warnings: Mutex::new(HashSet::new()),
             }),
             local: compiler,
-        }
+        })
     }
 }
 
-impl RemoteSharedMut {
-    fn receive_builders(base_url: &Option<reqwest::Url>) -> Result<Vec<BuilderInfo>, Error> {
-        match base_url {
-            Some(base_url) => {
-                let url = base_url.join(RPC_BUILDER_LIST).unwrap();
-                let mut response = reqwest::blocking::get(url).map_err(Error::other)?;
+impl RemoteShared {
+    // Create request to cluster service, signed if cluster token is configured.
+    fn request(
+        &self,
+        method: Method,
+        url: reqwest::Url,
+        body_size: u64,
+        body_digest: &str,
+    ) -> RequestBuilder {
+        let request = self.client.request(method.clone(), url.clone());
+        match &self.auth {
+            Some(auth) => auth.apply(request, method.as_str(), &url, body_size, body_digest),
+            None => request,
+        }
+    }
 
-                bincode::decode_from_std_read(&mut response, bincode::config::standard())
-                    .map_err(|e| Error::new(ErrorKind::InvalidData, e))
-            }
-            None => Ok(Vec::new()),
+    fn receive_builders(&self) -> Result<Vec<BuilderInfo>, Error> {
+        let builders = match (&self.base_url, &self.discovery) {
+            (Some(base_url), _) => self.receive_coordinator_builders(base_url)?,
+            (None, Some(discovery)) => discovery.builders(),
+            (None, None) => return Ok(Vec::new()),
+        };
+        Ok(builders
+            .into_iter()
+            .filter(|b| b.protocol == PROTOCOL_VERSION && b.matches(&self.selector))
+            .collect())
+    }
+
+    fn receive_coordinator_builders(
+        &self,
+        base_url: &reqwest::Url,
+    ) -> Result<Vec<BuilderInfo>, Error> {
+        let mut url = base_url.join(RPC_BUILDER_LIST).unwrap();
+        if !self.selector.is_empty() {
+            url.query_pairs_mut()
+                .append_pair(LABELS_PARAM, &self.selector.join(","));
+        }
+        let mut response = self
+            .request(Method::GET, url, 0, &body_digest(&[]))
+            .send()
+            .map_err(Error::other)?;
+        if !response.status().is_success() {
+            return Err(Error::other(format!(
+                "Coordinator responded with status {}: {}",
+                response.status(),
+                response.text().unwrap_or_default()
+            )));
         }
+
+        bincode::decode_from_std_read(&mut response, bincode::config::standard())
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+}
+
+// Listen to builder announcements if cluster has no coordinator.
+fn discovery_listener(config: &Config) -> Option<DiscoveryListener> {
+    if config.coordinator.is_some() {
+        return None;
     }
+    let group = config.cluster_discovery?;
+    DiscoveryListener::new(
+        group,
+        config.cluster_discovery_interface,
+        ClusterAuth::new(config),
+    )
+    .map_err(|e| warn!("Can't listen to builder announcements on {group}: {e}"))
+    .ok()
 }
 
 impl<C: Compiler> Compiler for RemoteCompiler<C> {
@@ -98,27 +216,69 @@ impl RemoteToolchain {
         &self,
         state: &SharedState,
         task: &CompileStep,
+        tried: &mut Vec<String>,
     ) -> Result<CompileResponse, Error> {
         let name = self
             .identifier()
             .ok_or_else(|| Error::other("Can't get toolchain name"))?;
 
-        let addr = self
-            .remote_endpoint(&name)
+        let (base_url, pending) = self
+            .remote_endpoint(&name, tried)
             .ok_or_else(|| Error::other("Can't find helper for toolchain"))?;
-        if task.pch_usage.is_some() {
-            return Err(Error::other(
-                "Remote precompiled header generation is not supported",
-            ));
-        }
+        tried.push(pending.endpoint.clone());
 
-        let base_url = get_base_url(&addr);
+        let mut result = self.send_task(state, task, name.clone(), &pending.endpoint, &base_url);
+        // Precompiled header may be evicted after upload check, so upload it again
+        if matches!(&result, Err(e) if is_precompiled_missing(e)) {
+            result = self.send_task(state, task, name, &pending.endpoint, &base_url);
+        }
+        let result = match result {
+            Ok(CompileResponse::UnknownToolchain(name)) => {
+                // Builder list is outdated, re-route task to another builder
+                self.refresh_builders();
+                Err(builder_unavailable(
+                    ErrorKind::NotFound,
+                    format!("Builder doesn't have toolchain: {name}"),
+                ))
+            }
+            result => result,
+        };
+        match &result {
+            Ok(_) => self.mark_healthy(&pending.endpoint),
+            Err(e) if is_builder_failure(e) => self.mark_failed(&pending.endpoint, e),
+            Err(_) => {}
+        }
+        drop(pending);
+        let result = result?;
+        state.statistic.inc_remote();
+        // Only builder, which has completed the task, is reported (not retries)
+        state.events.send(&Event::Remote {
+            output: task.output(),
+            builder: base_url.as_str(),
+        });
+        Ok(result)
+    }
 
+    fn send_task(
+        &self,
+        state: &SharedState,
+        task: &CompileStep,
+        name: String,
+        endpoint: &str,
+        base_url: &reqwest::Url,
+    ) -> Result<CompileResponse, Error> {
         let preprocessed = if let Preprocessed(preprocessed) = &task.input {
             preprocessed
         } else {
             unimplemented!()
         };
+        // Builders reject oversized tasks, so compile them locally
+        if preprocessed.len() as u64 > MAX_PREPROCESSED_SIZE {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "Preprocessed data is too large for remote compilation",
+            ));
+        }
 
         // Send compilation request.
         let request = CompileRequest {
@@ -128,34 +288,119 @@ impl RemoteToolchain {
                 .iter()
                 .map(|s| s.to_str().unwrap().to_string())
                 .collect(),
-            preprocessed_data: preprocessed.to_vec(),
+            preprocessed_size: preprocessed.len() as u64,
             precompiled_hash: self.upload_precompiled(
                 state,
                 &task.pch_usage.get_in_abs(),
-                &base_url,
+                base_url,
             )?,
+            precompiled_output: task.pch_usage.is_out(),
+            object_output: task.output_object.is_some(),
+        };
+        let header = bincode::encode_to_vec(&request, bincode::config::standard()).unwrap();
+        if header.len() > MAX_REQUEST_SIZE {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "Task arguments are too large for remote compilation",
+            ));
+        }
+        // Body digest is only needed to sign request
+        let digest = if self.shared.auth.is_some() {
+            let mut hasher = Sha256::new();
+            hasher.update(&header);
+            preprocessed.copy(&mut hasher)?;
+            hex::encode(hasher.finalize())
+        } else {
+            String::new()
         };
-        let request_payload =
-            bincode::encode_to_vec(&request, bincode::config::standard()).unwrap();
-        let mut resp: reqwest::blocking::Response = self
+        let remote_task = state.statistic.remote_task();
+        // Compress request only for builders which have told us they support it
+        let compress = self.shared.lz4_builders.read().unwrap().contains(endpoint);
+        let mut request = self
             .shared
-            .client
-            .post(base_url.join(RPC_BUILDER_TASK).unwrap())
-            .body(request_payload)
-            .send()
-            .map_err(Error::other)?;
-        // Receive compilation result.
+            .request(
+                Method::POST,
+                base_url.join(RPC_BUILDER_TASK).unwrap(),
+                header.len() as u64 + preprocessed.len() as u64,
+                &digest,
+            )
+            .header(ACCEPT_ENCODING, ENCODING_LZ4);
+        if compress {
+            request = request.header(CONTENT_ENCODING, ENCODING_LZ4);
+        }
+        // Stream preprocessed data to builder without copying it into request buffer
+        let (writer, reader) = pipe(REQUEST_PIPE_CAPACITY);
+        let resp = thread::scope(|scope| {
+            let producer = scope.spawn(|| {
+                write_body(writer, compress, |writer| {
+                    writer.write_all(&header)?;
+                    preprocessed.copy(writer).map(drop)
+                })
+            });
+            let resp = request
+                .body(reqwest::blocking::Body::new(reader))
+                .send()
+                .map_err(|e| builder_failure(Error::other(e)));
+            // Producer fails when request is interrupted, so request error is more relevant
+            let produced = producer.join().unwrap();
+            let resp = resp?;
+            produced.map(|()| resp)
+        })?;
+        if has_encoding(header_str(&resp, ACCEPT_ENCODING), ENCODING_LZ4) {
+            self.shared
+                .lz4_builders
+                .write()
+                .unwrap()
+                .insert(endpoint.to_string());
+        }
+        if resp.status() == StatusCode::SERVICE_UNAVAILABLE {
+            self.mark_busy(endpoint, retry_after(&resp));
+            return Err(builder_unavailable(
+                ErrorKind::ResourceBusy,
+                "Builder is busy".to_string(),
+            ));
+        }
+        if resp.status() == StatusCode::FORBIDDEN {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!(
+                    "Builder rejected task arguments: {}",
+                    resp.text().unwrap_or_default()
+                ),
+            ));
+        }
+        if resp.status() == StatusCode::FAILED_DEPENDENCY {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                RemoteError::PrecompiledMissing,
+            ));
+        }
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let error = Error::other(format!(
+                "Builder responded with status {status}: {}",
+                resp.text().unwrap_or_default()
+            ));
+            return Err(if status.is_server_error() {
+                builder_failure(error)
+            } else {
+                error
+            });
+        }
+        // Receive compilation result, output files follow it in response body.
+        let compressed = has_encoding(header_str(&resp, CONTENT_ENCODING), ENCODING_LZ4);
+        let mut body = BuilderReader(read_body(resp, compressed).map_err(builder_failure)?);
         let result: CompileResponse =
-            bincode::decode_from_std_read(&mut resp, bincode::config::standard())
-                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
-        if let CompileResponse::Success(ref output) = result {
-            write_output(
-                &task.output_object,
-                output.success(),
-                output.stdout.as_slice(),
-            )?;
+            bincode::decode_from_std_read(&mut body, bincode::config::standard()).map_err(|e| {
+                match e {
+                    bincode::error::DecodeError::Io { inner, .. } => inner,
+                    e => builder_failure(Error::new(ErrorKind::InvalidData, e)),
+                }
+            })?;
+        if let CompileResponse::Success { output, files } = &result {
+            write_outputs(task, output.success(), files, &mut body)?;
         }
-        state.statistic.inc_remote();
+        drop(remote_task);
         Ok(result)
     }
 
@@ -169,45 +414,53 @@ impl RemoteToolchain {
             Some(path) => {
                 // Get precompiled header file hash
                 let meta = state.cache.file_hash(path)?;
+                // Builders reject oversized uploads, so compile such tasks locally
+                if meta.size > MAX_PRECOMPILED_SIZE {
+                    return Err(Error::new(
+                        ErrorKind::PermissionDenied,
+                        "Precompiled header is too large for remote compilation",
+                    ));
+                }
                 // Check is precompiled header uploaded
                 // todo: this is workaround for https://github.com/hyperium/hyper/issues/838
+                let url = base_url
+                    .join(&format!("{RPC_BUILDER_UPLOAD}/{}", meta.hash))
+                    .unwrap();
                 match self
                     .shared
-                    .client
-                    .head(
-                        base_url
-                            .join(&format!("{RPC_BUILDER_UPLOAD}/{}", meta.hash))
-                            .unwrap(),
-                    )
+                    .request(Method::HEAD, url.clone(), 0, &body_digest(&[]))
                     .send()
                     .map(|response| response.status())
-                    .map_err(|e| Error::new(ErrorKind::BrokenPipe, e))?
+                    .map_err(|e| builder_failure(Error::new(ErrorKind::BrokenPipe, e)))?
                 {
                     StatusCode::OK | StatusCode::ACCEPTED => return Ok(Some(meta.hash)),
                     _ => {}
                 }
                 let file = File::open(path)?;
                 // Upload precompiled header
+                // Uploaded content hash is part of the url, so it is used as body digest
                 match self
                     .shared
-                    .client
-                    .post(
-                        base_url
-                            .join(&format!("{RPC_BUILDER_UPLOAD}/{}", meta.hash))
-                            .unwrap(),
-                    )
+                    .request(Method::POST, url, meta.size, &meta.hash)
                     // todo: this is workaround for https://github.com/hyperium/hyper/issues/838
                     //.header(Expect::Continue)
                     .body(reqwest::blocking::Body::sized(file, meta.size))
                     .send()
                     .map(|response| response.status())
-                    .map_err(|e| Error::new(ErrorKind::BrokenPipe, e))?
+                    .map_err(|e| builder_failure(Error::new(ErrorKind::BrokenPipe, e)))?
                 {
                     StatusCode::OK | StatusCode::ACCEPTED => Ok(Some(meta.hash)),
-                    status => Err(Error::new(
-                        ErrorKind::BrokenPipe,
-                        format!("Can't upload precompiled header: {status}"),
-                    )),
+                    status => {
+                        let error = Error::new(
+                            ErrorKind::BrokenPipe,
+                            format!("Can't upload precompiled header: {status}"),
+                        );
+                        Err(if status.is_server_error() {
+                            builder_failure(error)
+                        } else {
+                            error
+                        })
+                    }
                 }
             }
             None => Ok(None),
@@ -228,10 +481,16 @@ impl RemoteToolchain {
             if holder.cooldown >= now {
                 return holder.builders.clone();
             }
-            match RemoteSharedMut::receive_builders(&self.shared.base_url) {
+            match self.shared.receive_builders() {
                 Ok(builders) => {
                     holder.builders = Arc::new(builders);
-                    holder.cooldown = now + Duration::from_secs(5);
+                    // Announced builders are already in memory, so pick up new ones sooner
+                    holder.cooldown = now
+                        + if self.shared.discovery.is_some() {
+                            Duration::from_secs(1)
+                        } else {
+                            Duration::from_secs(5)
+                        };
                 }
                 Err(e) => {
                     holder.cooldown = now + Duration::from_secs(1);
@@ -241,18 +500,150 @@ impl RemoteToolchain {
             holder.builders.clone()
         }
     }
+
+    // Receive builder list from coordinator on next request.
+    fn refresh_builders(&self) {
+        self.shared.mutable.write().unwrap().cooldown = Instant::now();
+    }
+
+    // Don't send tasks to fully loaded builder for a while.
+    fn mark_busy(&self, endpoint: &str, delay: Duration) {
+        self.shared
+            .health
+            .lock()
+            .unwrap()
+            .entry(endpoint.to_string())
+            .or_default()
+            .block(Instant::now() + delay);
+    }
+
+    // Blacklist failed builder with exponential backoff and report failure to coordinator.
+    fn mark_failed(&self, endpoint: &str, error: &Error) {
+        // Builder may have been replaced by older version without compression support
+        self.shared.lz4_builders.write().unwrap().remove(endpoint);
+        let delay = {
+            let mut health = self.shared.health.lock().unwrap();
+            let entry = health.entry(endpoint.to_string()).or_default();
+            entry.failures = entry.failures.saturating_add(1);
+            let delay = FAILURE_BACKOFF_MIN
+                .saturating_mul(1 << entry.failures.min(16).saturating_sub(1))
+                .min(FAILURE_BACKOFF_MAX);
+            entry.block(Instant::now() + delay);
+            delay
+        };
+        warn!(
+            "Builder {endpoint} failed, don't use it for {}s: {error}",
+            delay.as_secs()
+        );
+
+        let Some(base_url) = &self.shared.base_url else {
+            return;
+        };
+        let url = base_url.join(RPC_BUILDER_FAILURE).unwrap();
+        let payload = bincode::encode_to_vec(
+            &BuilderFailure {
+                endpoint: endpoint.to_string(),
+            },
+            bincode::config::standard(),
+        )
+        .unwrap();
+        let request = self
+            .shared
+            .request(
+                Method::POST,
+                url,
+                payload.len() as u64,
+                &body_digest(&payload),
+            )
+            .body(payload);
+        // Don't delay the build on reporting.
+        thread::spawn(move || {
+            if let Err(e) = request.send() {
+                trace!("Can't report builder failure to coordinator: {e}");
+            }
+        });
+    }
+
+    fn mark_healthy(&self, endpoint: &str) {
+        self.shared.health.lock().unwrap().remove(endpoint);
+    }
+
     // Resolve toolchain for command execution.
-    fn remote_endpoint(&self, toolchain_name: &str) -> Option<SocketAddr> {
+    fn remote_endpoint(
+        &self,
+        toolchain_name: &str,
+        exclude: &[String],
+    ) -> Option<(reqwest::Url, PendingTask<'_>)> {
         let name = toolchain_name.to_string();
         let all_builders = self.builders();
-        let builder = all_builders
-            .iter()
-            .filter(|b| b.toolchains.contains(&name))
-            .choose(&mut rand::rng())?;
-        SocketAddr::from_str(&builder.endpoint).ok()
+        let now = Instant::now();
+        let health = self.shared.health.lock().unwrap();
+        let mut pending = self.shared.pending.lock().unwrap();
+        let builder = select_builder(
+            all_builders
+                .iter()
+                .filter(|b| b.toolchains.contains(&name))
+                .filter(|b| !exclude.contains(&b.endpoint))
+                .filter(|b| !health.get(&b.endpoint).is_some_and(|h| h.is_blocked(now))),
+            &pending,
+        )?;
+        let addr = SocketAddr::from_str(&builder.endpoint).ok()?;
+        *pending.entry(builder.endpoint.clone()).or_default() += 1;
+        Some((
+            get_base_url(&addr, builder.secure),
+            PendingTask {
+                shared: &self.shared,
+                endpoint: builder.endpoint.clone(),
+            },
+        ))
     }
 }
 
+fn header_str(response: &reqwest::blocking::Response, name: HeaderName) -> Option<&str> {
+    response.headers().get(name)?.to_str().ok()
+}
+
+// Delay from Retry-After header (only delay in seconds is supported).
+fn retry_after(response: &reqwest::blocking::Response) -> Duration {
+    response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok()?.trim().parse().ok())
+        .map_or(Duration::from_secs(1), Duration::from_secs)
+}
+
+// Request to builder in progress.
+struct PendingTask<'a> {
+    shared: &'a RemoteShared,
+    endpoint: String,
+}
+
+impl Drop for PendingTask<'_> {
+    fn drop(&mut self) {
+        let mut pending = self.shared.pending.lock().unwrap();
+        if let Some(count) = pending.get_mut(&self.endpoint) {
+            *count -= 1;
+            if *count == 0 {
+                pending.remove(&self.endpoint);
+            }
+        }
+    }
+}
+
+// Pick the least loaded builder, equally loaded builders are chosen randomly.
+fn select_builder<'a>(
+    builders: impl Iterator<Item = &'a BuilderInfo>,
+    pending: &HashMap<String, usize>,
+) -> Option<&'a BuilderInfo> {
+    let mut builders: Vec<&BuilderInfo> = builders.collect();
+    builders.shuffle(&mut rand::rng());
+    builders.into_iter().min_by(|a, b| {
+        let load_a = a.load(pending.get(&a.endpoint).copied().unwrap_or(0));
+        let load_b = b.load(pending.get(&b.endpoint).copied().unwrap_or(0));
+        load_a.total_cmp(&load_b)
+    })
+}
+
 impl Toolchain for RemoteToolchain {
     fn identifier(&self) -> Option<String> {
         self.local.identifier()
@@ -286,40 +677,338 @@ impl Toolchain for RemoteToolchain {
         self.local.create_compile_step(task, preprocessed)
     }
 
+    fn check_remote_args(
+        &self,
+        args: &[String],
+        output_precompiled: bool,
+    ) -> Result<Vec<String>, String> {
+        self.local.check_remote_args(args, output_precompiled)
+    }
+
     fn run_compile(&self, state: &SharedState, task: CompileStep) -> crate::Result<OutputInfo> {
-        match self.compile_remote(state, &task) {
-            Ok(response) => match response {
-                CompileResponse::Success(output) => Ok(output),
-                CompileResponse::Err(err) => Err(err.into()),
-            },
-            Err(e) => {
-                trace!("Fallback to local build: {e}");
-                self.local.run_compile(state, task)
+        let deadline = Instant::now() + REMOTE_DEADLINE;
+        let mut tried: Vec<String> = Vec::new();
+        loop {
+            let attempts = tried.len();
+            match self.compile_remote(state, &task, &mut tried) {
+                Ok(response) => {
+                    return match response {
+                        CompileResponse::Success { output, .. } => Ok(output),
+                        CompileResponse::Err(err) => Err(err.into()),
+                        CompileResponse::UnknownToolchain(name) => {
+                            Err(crate::Error::ToolchainNotFound(name.into()))
+                        }
+                    }
+                }
+                Err(e) => {
+                    // Retry only if request was sent to some builder, otherwise next attempt fails the same way
+                    // Rejected arguments and local errors won't change on another builder
+                    if tried.len() == attempts
+                        || !is_retryable(&e)
+                        || tried.len() >= REMOTE_ATTEMPTS
+                        || Instant::now() >= deadline
+                    {
+                        // Rejected arguments disable remote build for the task, so it shouldn't go unnoticed,
+                        // but same arguments are usually rejected for every task of the build
+                        if e.kind() == ErrorKind::PermissionDenied
+                            && self
+                                .shared
+                                .fallback_warnings
+                                .lock()
+                                .unwrap()
+                                .insert(e.to_string())
+                        {
+                            warn!("Fallback to local build: {e}");
+                        } else {
+                            trace!("Fallback to local build: {e}");
+                        }
+                        return self.local.run_compile(state, task);
+                    }
+                    trace!("Retry remote build on another builder: {e}");
+                    state.statistic.inc_retry();
+                }
             }
         }
     }
 }
 
-fn get_base_url(addr: &SocketAddr) -> reqwest::Url {
-    let mut url = reqwest::Url::from_str("http://localhost").unwrap();
+fn get_base_url(addr: &SocketAddr, secure: bool) -> reqwest::Url {
+    let mut url = reqwest::Url::from_str(if secure {
+        "https://localhost"
+    } else {
+        "http://localhost"
+    })
+    .unwrap();
     url.set_ip_host(addr.ip()).unwrap();
     url.set_port(Some(addr.port())).unwrap();
     url
 }
 
-fn write_output(path: &Option<PathBuf>, success: bool, output: &[u8]) -> Result<(), Error> {
-    match path {
-        Some(path) => {
-            if success {
-                let mut f = File::create(path)?;
-                f.write(output).inspect_err(|_| {
-                    drop(fs::remove_file(path));
-                })?;
-                Ok(())
-            } else {
-                fs::remove_file(path)
+// Write files produced by builder from response body to task output paths.
+// Stale outputs are removed if compilation or transfer fails.
+fn write_outputs(
+    task: &CompileStep,
+    success: bool,
+    files: &[OutputFile],
+    body: &mut impl Read,
+) -> Result<(), Error> {
+    let result = if success {
+        files
+            .iter()
+            .try_for_each(|file| write_output_file(task, file, body))
+    } else {
+        Ok(())
+    };
+    if !success || result.is_err() {
+        for path in [task.output_object.as_ref(), task.pch_usage.get_out_abs()]
+            .into_iter()
+            .flatten()
+        {
+            drop(fs::remove_file(path));
+        }
+    }
+    result
+}
+
+fn write_output_file(
+    task: &CompileStep,
+    file: &OutputFile,
+    body: &mut impl Read,
+) -> Result<(), Error> {
+    let path = match file.kind {
+        OutputFileKind::Object => task.output_object.as_ref(),
+        OutputFileKind::Precompiled => task.pch_usage.get_out_abs(),
+    }
+    .ok_or_else(|| {
+        builder_failure(Error::new(
+            ErrorKind::InvalidData,
+            format!("Unexpected output file: {:?}", file.kind),
+        ))
+    })?;
+    let written = std::io::copy(&mut body.take(file.size), &mut File::create(path)?);
+    if !matches!(written, Ok(size) if size == file.size) {
+        return Err(written.err().unwrap_or_else(|| {
+            builder_failure(Error::new(
+                ErrorKind::UnexpectedEof,
+                "Unexpected end of output file",
+            ))
+        }));
+    }
+    Ok(())
+}
+
+// Remote task error reason, which decides how task is retried. Other errors are local
+// and fall back to local build, so they must not hide healthy builder.
+#[derive(Debug)]
+enum RemoteError {
+    // Builder or connection to it failed, blacklists builder
+    Builder(Error),
+    // Builder can't take task now, try another one
+    Unavailable(String),
+    // Builder doesn't have uploaded precompiled header anymore, upload it again
+    PrecompiledMissing,
+}
+
+impl std::fmt::Display for RemoteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RemoteError::Builder(e) => e.fmt(f),
+            RemoteError::Unavailable(message) => f.write_str(message),
+            RemoteError::PrecompiledMissing => {
+                f.write_str("Builder doesn't have precompiled header")
             }
         }
-        None => Ok(()),
+    }
+}
+
+impl std::error::Error for RemoteError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RemoteError::Builder(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+fn remote_error(error: &Error) -> Option<&RemoteError> {
+    error.get_ref()?.downcast_ref()
+}
+
+fn builder_failure(error: Error) -> Error {
+    if remote_error(&error).is_some() {
+        return error;
+    }
+    Error::new(error.kind(), RemoteError::Builder(error))
+}
+
+fn builder_unavailable(kind: ErrorKind, message: String) -> Error {
+    Error::new(kind, RemoteError::Unavailable(message))
+}
+
+fn is_builder_failure(error: &Error) -> bool {
+    matches!(remote_error(error), Some(RemoteError::Builder(_)))
+}
+
+fn is_precompiled_missing(error: &Error) -> bool {
+    matches!(remote_error(error), Some(RemoteError::PrecompiledMissing))
+}
+
+// Task may succeed on another builder.
+fn is_retryable(error: &Error) -> bool {
+    matches!(
+        remote_error(error),
+        Some(RemoteError::Builder(_) | RemoteError::Unavailable(_))
+    )
+}
+
+// Response body, which reports read errors as builder failures.
+struct BuilderReader<R: Read>(R);
+
+impl<R: Read> Read for BuilderReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.read(buf).map_err(builder_failure)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use crate::cluster::builder::{OutputFile, OutputFileKind};
+    use std::io::{Error, ErrorKind};
+
+    use crate::cluster::client::{
+        builder_failure, builder_unavailable, is_builder_failure, is_precompiled_missing,
+        is_retryable, select_builder, write_outputs, RemoteError,
+    };
+    use crate::cluster::common::BuilderInfo;
+    use crate::compiler::CompileInput::Preprocessed;
+    use crate::compiler::{CompileStep, CompilerOutput, PCHArgs, PCHUsage};
+
+    fn builder(endpoint: &str, cores: u32, free_slots: u32, queue_length: u32) -> BuilderInfo {
+        BuilderInfo {
+            name: endpoint.to_string(),
+            endpoint: endpoint.to_string(),
+            cores,
+            slots: cores,
+            free_slots,
+            queue_length,
+            ..BuilderInfo::default()
+        }
+    }
+
+    #[test]
+    fn test_select_builder_least_load() {
+        let builders = [
+            builder("laptop", 4, 0, 0),
+            builder("server", 64, 32, 0),
+            builder("busy", 64, 0, 16),
+        ];
+        let pending = HashMap::new();
+        assert_eq!(
+            select_builder(builders.iter(), &pending).unwrap().endpoint,
+            "server"
+        );
+
+        // Tasks already sent by this client count as load
+        let pending = HashMap::from([("server".to_string(), 64)]);
+        assert_eq!(
+            select_builder(builders.iter(), &pending).unwrap().endpoint,
+            "laptop"
+        );
+
+        assert!(select_builder(std::iter::empty(), &pending).is_none());
+    }
+
+    #[test]
+    fn test_write_outputs() {
+        let dir = tempfile::tempdir().unwrap();
+        let object = dir.path().join("sample.o");
+        let task = CompileStep {
+            args: Vec::new(),
+            output_object: Some(object.clone()),
+            pch_usage: PCHUsage::None,
+            input: Preprocessed(CompilerOutput::Vec(Vec::new())),
+            run_second_cpp: false,
+            clear_env: true,
+        };
+        let file = |kind| OutputFile { kind, size: 6 };
+        let mut body: &[u8] = b"objectrest";
+        write_outputs(&task, true, &[file(OutputFileKind::Object)], &mut body).unwrap();
+        assert_eq!(std::fs::read(&object).unwrap(), b"object");
+        assert_eq!(body, b"rest");
+
+        // Truncated response body is builder failure
+        let error =
+            write_outputs(&task, true, &[file(OutputFileKind::Object)], &mut body).unwrap_err();
+        assert!(is_builder_failure(&error));
+        assert!(!object.exists());
+
+        // Local file errors don't blacklist builder
+        let missing = CompileStep {
+            output_object: Some(dir.path().join("missing").join("sample.o")),
+            ..task
+        };
+        let mut body: &[u8] = b"object";
+        let error =
+            write_outputs(&missing, true, &[file(OutputFileKind::Object)], &mut body).unwrap_err();
+        assert!(!is_builder_failure(&error));
+        let task = CompileStep {
+            output_object: Some(object.clone()),
+            ..missing
+        };
+
+        // Task doesn't expect precompiled header
+        let mut body: &[u8] = b"object";
+        assert!(
+            write_outputs(&task, true, &[file(OutputFileKind::Precompiled)], &mut body).is_err()
+        );
+
+        // Failed compilation removes stale object
+        std::fs::write(&object, b"stale").unwrap();
+        write_outputs(&task, false, &[], &mut body).unwrap();
+        assert!(!object.exists());
+
+        // Partially written response doesn't leave precompiled header
+        let pch = dir.path().join("sample.pch");
+        let task = CompileStep {
+            pch_usage: PCHUsage::Out(PCHArgs {
+                path: pch.clone(),
+                path_abs: pch.clone(),
+                marker: None,
+            }),
+            ..task
+        };
+        let mut body: &[u8] = b"pchpchobj";
+        let files = [
+            file(OutputFileKind::Precompiled),
+            file(OutputFileKind::Object),
+        ];
+        assert!(write_outputs(&task, true, &files, &mut body).is_err());
+        assert!(!pch.exists());
+        assert!(!object.exists());
+    }
+
+    #[test]
+    fn test_retryable() {
+        let failure = builder_failure(Error::from(ErrorKind::ConnectionReset));
+        assert!(is_builder_failure(&failure));
+        assert!(is_retryable(&failure));
+        // Wrapping twice keeps error reason
+        assert!(is_builder_failure(&builder_failure(failure)));
+
+        let busy = builder_unavailable(ErrorKind::ResourceBusy, "Builder is busy".to_string());
+        assert!(is_retryable(&busy));
+        assert!(!is_builder_failure(&busy));
+
+        let missing = Error::new(ErrorKind::NotFound, RemoteError::PrecompiledMissing);
+        assert!(is_precompiled_missing(&missing));
+        assert!(!is_retryable(&missing));
+
+        // Local file errors are neither retried nor trigger precompiled header upload
+        let local = Error::from(ErrorKind::NotFound);
+        assert!(!is_precompiled_missing(&local));
+        assert!(!is_retryable(&local));
+        assert!(!is_builder_failure(&local));
     }
 }
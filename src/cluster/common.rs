@@ -1,13 +1,35 @@
+use std::fs;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+
 use bincode::{Decode, Encode};
 use uuid::Uuid;
 
+use crate::config::Config;
+
+// Version of cluster RPC payloads, must be increased on any incompatible change.
+pub const PROTOCOL_VERSION: u32 = 2;
+// Header with sender protocol version.
+pub const PROTOCOL_HEADER: &str = "X-Octobuild-Protocol";
+
 pub const RPC_BUILDER_UPDATE: &str = "/rpc/v1/builder/update";
 pub const RPC_BUILDER_LIST: &str = "/rpc/v1/builder/list";
+pub const RPC_BUILDER_FAILURE: &str = "/rpc/v1/builder/failure";
+// Query parameter of builder list request with comma-separated label selector.
+pub const LABELS_PARAM: &str = "labels";
+// Coordinator cluster state in JSON, HTML version is served at root.
+pub const COORDINATOR_STATUS: &str = "/status";
 
 pub const RPC_BUILDER_TASK: &str = "/rpc/v1/builder/task";
 pub const RPC_BUILDER_UPLOAD: &str = "/rpc/v1/builder/upload";
+// Builder drain mode: POST starts draining, DELETE resumes accepting tasks.
+pub const ADMIN_BUILDER_DRAIN: &str = "/admin/drain";
 
-#[derive(Decode, Encode)]
+// Content encoding for compressed task request and response bodies.
+pub const ENCODING_LZ4: &str = "lz4";
+
+#[derive(Clone, Decode, Encode)]
 pub struct BuilderInfo {
     // Agent name
     pub name: String,
@@ -15,8 +37,61 @@ pub struct BuilderInfo {
     pub endpoint: String,
     // Agent version
     pub version: String,
+    // Agent protocol version
+    pub protocol: u32,
     // Agent toolchain list
     pub toolchains: Vec<String>,
+    // Agent CPU core count
+    pub cores: u32,
+    // Count of task slots (maximum concurrently running compilations)
+    pub slots: u32,
+    // Count of idle task slots
+    pub free_slots: u32,
+    // Count of tasks waiting for a free slot
+    pub queue_length: u32,
+    // Agent serves HTTPS
+    pub secure: bool,
+    // Agent labels for routing tasks to builder pools
+    pub labels: Vec<String>,
+    // Agent finishes running tasks before shutdown and doesn't accept new ones
+    pub draining: bool,
+}
+
+impl Default for BuilderInfo {
+    fn default() -> Self {
+        BuilderInfo {
+            name: String::new(),
+            endpoint: String::new(),
+            version: String::new(),
+            protocol: PROTOCOL_VERSION,
+            toolchains: Vec::new(),
+            cores: 0,
+            slots: 0,
+            free_slots: 0,
+            queue_length: 0,
+            secure: false,
+            labels: Vec::new(),
+            draining: false,
+        }
+    }
+}
+
+impl BuilderInfo {
+    // Relative builder load: busy and queued tasks (including `pending` tasks not yet reported) per slot.
+    #[must_use]
+    pub fn load(&self, pending: usize) -> f64 {
+        let busy = self.slots.saturating_sub(self.free_slots) + self.queue_length;
+        (f64::from(busy) + pending as f64) / f64::from(self.slots.max(1))
+    }
+
+    // Check builder labels against selector: plain entries are required, `!label` entries are excluded.
+    #[must_use]
+    pub fn matches(&self, selector: &[String]) -> bool {
+        selector.iter().all(|entry| match entry.strip_prefix('!') {
+            Some(excluded) => !self.labels.iter().any(|label| label == excluded),
+            None => self.labels.contains(entry),
+        })
+    }
 }
 
 #[derive(Decode, Encode)]
@@ -36,3 +111,279 @@ impl BuilderInfoUpdate {
         }
     }
 }
+
+// Client report about failed builder.
+#[derive(Decode, Encode)]
+pub struct BuilderFailure {
+    // Failed builder endpoint
+    pub endpoint: String,
+}
+
+// Check protocol version header of received request.
+pub fn check_protocol(header: Option<&str>) -> Result<(), String> {
+    let version = header.and_then(|v| v.trim().parse::<u32>().ok());
+    if version == Some(PROTOCOL_VERSION) {
+        return Ok(());
+    }
+    Err(format!(
+        "Incompatible protocol version: expected {PROTOCOL_VERSION}, got {}",
+        version.map_or_else(|| "none".to_string(), |v| v.to_string())
+    ))
+}
+
+// HTTP client for cluster RPC requests.
+pub fn http_client(config: &Config) -> crate::Result<reqwest::blocking::Client> {
+    let mut headers = reqwest::header::HeaderMap::new();
+    headers.insert(PROTOCOL_HEADER, PROTOCOL_VERSION.into());
+    let mut builder = reqwest::blocking::Client::builder().default_headers(headers);
+    if let Some(path) = &config.cluster_tls_ca {
+        let certificate = reqwest::Certificate::from_pem(&read_file(path)?)?;
+        builder = builder.tls_certs_merge([certificate]);
+    }
+    Ok(builder.build()?)
+}
+
+// PEM certificate chain and private key for cluster HTTPS server, if configured.
+pub fn tls_identity(config: &Config) -> crate::Result<Option<(Vec<u8>, Vec<u8>)>> {
+    match (&config.cluster_tls_cert, &config.cluster_tls_key) {
+        (Some(cert), Some(key)) => Ok(Some((read_file(cert)?, read_file(key)?))),
+        (None, None) => Ok(None),
+        _ => Err(crate::Error::from(
+            "Both cluster_tls_cert and cluster_tls_key must be set to enable TLS",
+        )),
+    }
+}
+
+// Start cluster HTTP server, with TLS if certificate is configured.
+pub fn http_server<F>(
+    addr: SocketAddr,
+    identity: Option<(Vec<u8>, Vec<u8>)>,
+    handler: F,
+) -> crate::Result<rouille::Server<F>>
+where
+    F: Send + Sync + 'static + Fn(&rouille::Request) -> rouille::Response,
+{
+    match identity {
+        #[cfg(feature = "tls")]
+        Some((cert, key)) => rouille::Server::new_ssl(addr, handler, cert, key),
+        #[cfg(not(feature = "tls"))]
+        Some(_) => {
+            return Err(crate::Error::from(
+                "Cluster TLS requires octobuild built with `tls` feature",
+            ))
+        }
+        None => rouille::Server::new(addr, handler),
+    }
+    .map_err(|e| crate::Error::Generic(e.to_string()))
+}
+
+fn read_file(path: &Path) -> crate::Result<Vec<u8>> {
+    fs::read(path).map_err(|e| crate::Error::FileOpen {
+        path: path.to_path_buf(),
+        error: Box::new(e.into()),
+    })
+}
+
+// Error response for request from incompatible client, builder or coordinator.
+#[must_use]
+pub fn protocol_error(request: &rouille::Request) -> Option<rouille::Response> {
+    check_protocol(request.header(PROTOCOL_HEADER))
+        .err()
+        .map(|message| rouille::Response::text(message).with_status_code(400))
+}
+
+// Check whether `Accept-Encoding`/`Content-Encoding` header value contains given encoding.
+#[must_use]
+pub fn has_encoding(header: Option<&str>, encoding: &str) -> bool {
+    header.is_some_and(|value| {
+        value
+            .split(',')
+            .any(|v| v.split(';').next().unwrap_or("").trim() == encoding)
+    })
+}
+
+// Write request or response body produced by `write`, compressing it with lz4 if requested.
+pub fn write_body<W: Write>(
+    mut writer: W,
+    compress: bool,
+    write: impl FnOnce(&mut dyn Write) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    if compress {
+        let mut encoder = lz4::EncoderBuilder::new().level(1).build(writer)?;
+        write(&mut encoder)?;
+        let (mut writer, result) = encoder.finish();
+        result?;
+        writer.flush()
+    } else {
+        write(&mut writer)?;
+        writer.flush()
+    }
+}
+
+// Read request or response body, decompressing it if it was compressed with lz4.
+pub fn read_body<'a, R: Read + 'a>(
+    reader: R,
+    compressed: bool,
+) -> std::io::Result<Box<dyn Read + 'a>> {
+    if compressed {
+        return Ok(Box::new(lz4::Decoder::new(reader)?));
+    }
+    Ok(Box::new(reader))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use std::path::PathBuf;
+
+    use crate::cluster::common::{
+        check_protocol, has_encoding, http_client, http_server, read_body, tls_identity,
+        write_body, BuilderInfo, ENCODING_LZ4, PROTOCOL_VERSION,
+    };
+    use crate::config::Config;
+
+    fn tls_fixture(name: &str) -> Option<PathBuf> {
+        Some(
+            PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/tls")
+                .join(name),
+        )
+    }
+
+    #[test]
+    fn test_lz4_roundtrip() {
+        let data = b"int main() { return 0; }\n".repeat(100);
+        let mut compressed = Vec::new();
+        write_body(&mut compressed, true, |writer| writer.write_all(&data)).unwrap();
+        assert!(compressed.len() < data.len());
+        let mut decompressed = Vec::new();
+        read_body(compressed.as_slice(), true)
+            .unwrap()
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, data);
+    }
+
+    #[test]
+    fn test_builder_matches() {
+        let builder = BuilderInfo {
+            name: "ci".to_string(),
+            endpoint: "127.0.0.1:3001".to_string(),
+            cores: 8,
+            slots: 8,
+            free_slots: 8,
+            labels: vec!["linux".to_string(), "ps5".to_string()],
+            ..BuilderInfo::default()
+        };
+        let selector = |v: &[&str]| v.iter().map(ToString::to_string).collect::<Vec<_>>();
+        assert!(builder.matches(&[]));
+        assert!(builder.matches(&selector(&["linux", "ps5"])));
+        assert!(builder.matches(&selector(&["linux", "!workstation"])));
+        assert!(!builder.matches(&selector(&["windows"])));
+        assert!(!builder.matches(&selector(&["!ps5"])));
+    }
+
+    #[test]
+    fn test_builder_load() {
+        let mut builder = BuilderInfo {
+            name: "server".to_string(),
+            endpoint: "127.0.0.1:3001".to_string(),
+            cores: 64,
+            slots: 8,
+            free_slots: 8,
+            ..BuilderInfo::default()
+        };
+        // Builder with process limit below core count is idle with all slots free
+        assert!(builder.load(0).abs() < f64::EPSILON);
+        assert!((builder.load(4) - 0.5).abs() < f64::EPSILON);
+        builder.free_slots = 0;
+        builder.queue_length = 4;
+        assert!((builder.load(0) - 1.5).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_has_encoding() {
+        assert!(has_encoding(Some("lz4"), ENCODING_LZ4));
+        assert!(has_encoding(Some("gzip, lz4;q=0.5"), ENCODING_LZ4));
+        assert!(!has_encoding(Some("gzip"), ENCODING_LZ4));
+        assert!(!has_encoding(None, ENCODING_LZ4));
+    }
+
+    #[test]
+    fn test_check_protocol() {
+        assert!(check_protocol(Some(&PROTOCOL_VERSION.to_string())).is_ok());
+        assert_eq!(
+            check_protocol(None).unwrap_err(),
+            format!("Incompatible protocol version: expected {PROTOCOL_VERSION}, got none")
+        );
+        assert!(check_protocol(Some("1")).is_err());
+        assert!(check_protocol(Some("foo")).is_err());
+    }
+
+    #[test]
+    fn test_tls_config() {
+        let mut config = Config::default();
+        assert!(tls_identity(&config).unwrap().is_none());
+
+        config.cluster_tls_cert = tls_fixture("cert.pem");
+        assert!(tls_identity(&config).is_err());
+
+        config.cluster_tls_key = tls_fixture("key.pem");
+        assert!(tls_identity(&config).unwrap().is_some());
+
+        // Self-signed certificate as trusted CA
+        config.cluster_tls_ca = tls_fixture("cert.pem");
+        assert!(http_client(&config).is_ok());
+
+        config.cluster_tls_ca = tls_fixture("missing.pem");
+        assert!(http_client(&config).is_err());
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn test_tls_loopback() {
+        let config = Config {
+            cluster_tls_cert: tls_fixture("cert.pem"),
+            cluster_tls_key: tls_fixture("key.pem"),
+            cluster_tls_ca: tls_fixture("cert.pem"),
+            ..Config::default()
+        };
+        let server = http_server(
+            "127.0.0.1:0".parse().unwrap(),
+            tls_identity(&config).unwrap(),
+            |_| rouille::Response::text("ok"),
+        )
+        .unwrap();
+        let url = format!("https://127.0.0.1:{}/", server.server_addr().port());
+        let (handle, stop) = server.stoppable();
+
+        let response = http_client(&config).unwrap().get(&url).send().unwrap();
+        assert_eq!(response.text().unwrap(), "ok");
+        // Self-signed certificate isn't trusted without configured CA
+        assert!(http_client(&Config::default())
+            .unwrap()
+            .get(&url)
+            .send()
+            .is_err());
+
+        stop.send(()).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[cfg(not(feature = "tls"))]
+    #[test]
+    fn test_tls_disabled() {
+        let config = Config {
+            cluster_tls_cert: tls_fixture("cert.pem"),
+            cluster_tls_key: tls_fixture("key.pem"),
+            ..Config::default()
+        };
+        assert!(http_server(
+            "127.0.0.1:0".parse().unwrap(),
+            tls_identity(&config).unwrap(),
+            |_| rouille::Response::text("ok"),
+        )
+        .is_err());
+    }
+}
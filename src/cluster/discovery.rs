@@ -0,0 +1,319 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{trace, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::cluster::auth::{body_digest, unix_time, ClusterAuth};
+use crate::cluster::common::BuilderInfo;
+
+// Builder is forgotten if it wasn't announced for this time.
+const ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(5);
+// Max size of announcement datagram.
+const MAX_PACKET_SIZE: usize = 0x10000;
+// Interval between checks whether listener is still in use.
+const LISTEN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+// Pseudo request method and path for announcement signature.
+const ANNOUNCE_METHOD: &str = "ANNOUNCE";
+const ANNOUNCE_PATH: &str = "/discovery";
+
+// Builder information multicast by builder without coordinator.
+#[derive(bincode::Encode, bincode::Decode)]
+struct Announcement {
+    timestamp: u64,
+    // Signature of builder information, if cluster token is configured
+    signature: Option<String>,
+    info: BuilderInfo,
+}
+
+impl Announcement {
+    fn digest(info: &BuilderInfo) -> String {
+        body_digest(&bincode::encode_to_vec(info, bincode::config::standard()).unwrap())
+    }
+}
+
+// Sends builder information to multicast group.
+pub struct DiscoveryAnnouncer {
+    socket: UdpSocket,
+    group: SocketAddrV4,
+}
+
+impl DiscoveryAnnouncer {
+    pub fn new(group: SocketAddrV4, interface: Ipv4Addr) -> Result<Self> {
+        check_group(group)?;
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_multicast_if_v4(&interface)?;
+        // Deliver announcements to clients on the same host
+        socket.set_multicast_loop_v4(true)?;
+        socket.bind(&SocketAddr::V4(SocketAddrV4::new(interface, 0)).into())?;
+        Ok(DiscoveryAnnouncer {
+            socket: socket.into(),
+            group,
+        })
+    }
+
+    pub fn announce(&self, info: &BuilderInfo, auth: Option<&ClusterAuth>) -> Result<()> {
+        let timestamp = unix_time();
+        let announcement = Announcement {
+            timestamp,
+            signature: auth.map(|auth| {
+                auth.sign(
+                    ANNOUNCE_METHOD,
+                    ANNOUNCE_PATH,
+                    timestamp,
+                    &Announcement::digest(info),
+                )
+            }),
+            info: info.clone(),
+        };
+        let payload = bincode::encode_to_vec(&announcement, bincode::config::standard())
+            .map_err(Error::other)?;
+        self.socket.send_to(&payload, self.group)?;
+        Ok(())
+    }
+}
+
+struct DiscoveredBuilder {
+    info: BuilderInfo,
+    timeout: Instant,
+}
+
+#[derive(Default)]
+struct DiscoveryState {
+    // Announced builders by endpoint
+    builders: HashMap<String, DiscoveredBuilder>,
+}
+
+impl DiscoveryState {
+    fn receive(
+        &mut self,
+        data: &[u8],
+        source: SocketAddr,
+        auth: Option<&ClusterAuth>,
+        now: Instant,
+    ) -> std::result::Result<(), String> {
+        let (mut announcement, _): (Announcement, usize) =
+            bincode::decode_from_slice(data, bincode::config::standard())
+                .map_err(|e| format!("Can't parse announcement: {e}"))?;
+        if let Some(auth) = auth {
+            auth.verify(
+                ANNOUNCE_METHOD,
+                ANNOUNCE_PATH,
+                Some(&announcement.timestamp.to_string()),
+                announcement.signature.as_deref(),
+                &Announcement::digest(&announcement.info),
+            )?;
+        }
+        // Fix unspecified endpoint IP address.
+        let endpoint = SocketAddr::from_str(&announcement.info.endpoint)
+            .map_err(|e| format!("Can't parse endpoint address: {e}"))?;
+        if endpoint.ip().is_unspecified() {
+            announcement.info.endpoint = SocketAddr::new(source.ip(), endpoint.port()).to_string();
+        }
+
+        self.builders.retain(|_, e| e.timeout >= now);
+        self.builders.insert(
+            announcement.info.endpoint.clone(),
+            DiscoveredBuilder {
+                info: announcement.info,
+                timeout: now + ANNOUNCE_TIMEOUT,
+            },
+        );
+        Ok(())
+    }
+
+    fn builders(&self, now: Instant) -> Vec<BuilderInfo> {
+        let mut builders: Vec<BuilderInfo> = self
+            .builders
+            .values()
+            .filter(|e| e.timeout >= now && !e.info.draining)
+            .map(|e| e.info.clone())
+            .collect();
+        builders.sort_by(|a, b| a.endpoint.cmp(&b.endpoint));
+        builders
+    }
+}
+
+/// Listener of builder announcements from multicast group.
+///
+/// Keeps recently announced builders, so clients don't need coordinator.
+pub struct DiscoveryListener {
+    state: Arc<Mutex<DiscoveryState>>,
+}
+
+impl DiscoveryListener {
+    pub fn new(
+        group: SocketAddrV4,
+        interface: Ipv4Addr,
+        auth: Option<ClusterAuth>,
+    ) -> Result<Self> {
+        check_group(group)?;
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        // Several clients on the same host listen to the same port
+        socket.set_reuse_address(true)?;
+        socket
+            .bind(&SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, group.port())).into())?;
+        socket.join_multicast_v4(group.ip(), &interface)?;
+        socket.set_read_timeout(Some(LISTEN_POLL_INTERVAL))?;
+        let socket: UdpSocket = socket.into();
+
+        let state = Arc::new(Mutex::new(DiscoveryState::default()));
+        let weak = Arc::downgrade(&state);
+        thread::spawn(move || DiscoveryListener::listen(&socket, &weak, auth.as_ref()));
+        Ok(DiscoveryListener { state })
+    }
+
+    // Receive announcements until listener is dropped.
+    fn listen(socket: &UdpSocket, state: &Weak<Mutex<DiscoveryState>>, auth: Option<&ClusterAuth>) {
+        let mut buffer = vec![0; MAX_PACKET_SIZE];
+        loop {
+            let received = socket.recv_from(&mut buffer);
+            let Some(state) = state.upgrade() else {
+                return;
+            };
+            match received {
+                Ok((size, source)) => {
+                    let result = state.lock().unwrap().receive(
+                        &buffer[..size],
+                        source,
+                        auth,
+                        Instant::now(),
+                    );
+                    if let Err(e) = result {
+                        trace!("Ignore builder announcement from {source}: {e}");
+                    }
+                }
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(e) => {
+                    warn!("Can't receive builder announcements: {e}");
+                    thread::sleep(LISTEN_POLL_INTERVAL);
+                }
+            }
+        }
+    }
+
+    // Builders announced recently, except draining ones.
+    #[must_use]
+    pub fn builders(&self) -> Vec<BuilderInfo> {
+        self.state.lock().unwrap().builders(Instant::now())
+    }
+}
+
+fn check_group(group: SocketAddrV4) -> Result<()> {
+    if group.ip().is_multicast() {
+        Ok(())
+    } else {
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Discovery address is not multicast: {group}"),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::cluster::auth::ClusterAuth;
+    use crate::cluster::common::BuilderInfo;
+    use crate::cluster::discovery::{
+        Announcement, DiscoveryAnnouncer, DiscoveryListener, DiscoveryState, ANNOUNCE_TIMEOUT,
+    };
+
+    fn info(endpoint: &str) -> BuilderInfo {
+        BuilderInfo {
+            name: "builder".to_string(),
+            endpoint: endpoint.to_string(),
+            toolchains: vec!["clang".to_string()],
+            cores: 8,
+            slots: 8,
+            free_slots: 8,
+            ..BuilderInfo::default()
+        }
+    }
+
+    #[test]
+    fn test_receive_announcement() {
+        let auth = ClusterAuth::from_token("secret");
+        let source = "192.168.1.10:40000".parse().unwrap();
+        let now = Instant::now();
+        let encode = |announcement: &Announcement| {
+            bincode::encode_to_vec(announcement, bincode::config::standard()).unwrap()
+        };
+        let mut state = DiscoveryState::default();
+
+        // Unsigned announcement is ignored when cluster token is configured
+        let unsigned = Announcement {
+            timestamp: 0,
+            signature: None,
+            info: info("0.0.0.0:3001"),
+        };
+        assert!(state
+            .receive(&encode(&unsigned), source, Some(&auth), now)
+            .is_err());
+        state
+            .receive(&encode(&unsigned), source, None, now)
+            .unwrap();
+        assert_eq!(state.builders(now)[0].endpoint, "192.168.1.10:3001");
+
+        let mut draining = info("192.168.1.11:3001");
+        draining.draining = true;
+        state
+            .receive(
+                &encode(&Announcement {
+                    timestamp: 0,
+                    signature: None,
+                    info: draining,
+                }),
+                source,
+                None,
+                now,
+            )
+            .unwrap();
+        assert_eq!(state.builders(now).len(), 1);
+        assert!(state
+            .builders(now + ANNOUNCE_TIMEOUT + Duration::from_secs(1))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_loopback_discovery() {
+        let port = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let group = SocketAddrV4::new(Ipv4Addr::new(239, 255, 77, 77), port);
+        let interface = Ipv4Addr::LOCALHOST;
+        // Listeners share the port like separate client processes on one host
+        let listeners: Vec<DiscoveryListener> = (0..2)
+            .map(|_| {
+                DiscoveryListener::new(group, interface, Some(ClusterAuth::from_token("secret")))
+                    .unwrap()
+            })
+            .collect();
+        let announcer = DiscoveryAnnouncer::new(group, interface).unwrap();
+        let auth = ClusterAuth::from_token("secret");
+        let builder = info("0.0.0.0:3001");
+        for _ in 0..50 {
+            announcer.announce(&builder, Some(&auth)).unwrap();
+            if listeners.iter().all(|l| !l.builders().is_empty()) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        for listener in &listeners {
+            let builders = listener.builders();
+            assert_eq!(builders.len(), 1);
+            assert_eq!(builders[0].endpoint, "127.0.0.1:3001");
+        }
+        assert!(DiscoveryAnnouncer::new(SocketAddrV4::new(interface, port), interface).is_err());
+    }
+}
@@ -0,0 +1,140 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// Content type of Prometheus text exposition format.
+pub const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+// HTTP path of metrics endpoint for Prometheus scraping.
+pub const METRICS_PATH: &str = "/metrics";
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    #[must_use]
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// Histogram of durations with fixed bucket bounds in seconds.
+pub struct Histogram {
+    bounds: &'static [f64],
+    buckets: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    #[must_use]
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            buckets: bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(index) = self.bounds.iter().position(|bound| seconds <= *bound) {
+            self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum_micros.fetch_add(
+            u64::try_from(duration.as_micros()).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Writer of metrics in Prometheus text exposition format.
+#[derive(Default)]
+pub struct MetricsWriter {
+    output: String,
+}
+
+impl MetricsWriter {
+    pub fn counter(&mut self, name: &str, help: &str, value: u64) {
+        self.header(name, help, "counter");
+        _ = writeln!(self.output, "{name} {value}");
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, value: f64) {
+        self.header(name, help, "gauge");
+        _ = writeln!(self.output, "{name} {value}");
+    }
+
+    pub fn histogram(&mut self, name: &str, help: &str, histogram: &Histogram) {
+        self.header(name, help, "histogram");
+        // Prometheus buckets are cumulative
+        let mut total = 0;
+        for (bound, bucket) in histogram.bounds.iter().zip(&histogram.buckets) {
+            total += bucket.load(Ordering::Relaxed);
+            _ = writeln!(self.output, "{name}_bucket{{le=\"{bound}\"}} {total}");
+        }
+        let count = histogram.count.load(Ordering::Relaxed);
+        let sum = histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        _ = writeln!(self.output, "{name}_bucket{{le=\"+Inf\"}} {count}");
+        _ = writeln!(self.output, "{name}_sum {sum}");
+        _ = writeln!(self.output, "{name}_count {count}");
+    }
+
+    #[must_use]
+    pub fn finish(self) -> String {
+        self.output
+    }
+
+    fn header(&mut self, name: &str, help: &str, kind: &str) {
+        _ = writeln!(self.output, "# HELP {name} {help}");
+        _ = writeln!(self.output, "# TYPE {name} {kind}");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::cluster::metrics::{Counter, Histogram, MetricsWriter};
+
+    #[test]
+    fn test_metrics_format() {
+        let counter = Counter::default();
+        counter.inc();
+        counter.add(2);
+        let histogram = Histogram::new(&[0.5, 1.0]);
+        histogram.observe(Duration::from_millis(100));
+        histogram.observe(Duration::from_millis(700));
+        histogram.observe(Duration::from_secs(3));
+
+        let mut writer = MetricsWriter::default();
+        writer.counter("tasks_total", "Received tasks.", counter.get());
+        writer.gauge("queue_length", "Queued tasks.", 2.0);
+        writer.histogram("duration_seconds", "Task duration.", &histogram);
+        assert_eq!(
+            writer.finish(),
+            "# HELP tasks_total Received tasks.\n\
+             # TYPE tasks_total counter\n\
+             tasks_total 3\n\
+             # HELP queue_length Queued tasks.\n\
+             # TYPE queue_length gauge\n\
+             queue_length 2\n\
+             # HELP duration_seconds Task duration.\n\
+             # TYPE duration_seconds histogram\n\
+             duration_seconds_bucket{le=\"0.5\"} 1\n\
+             duration_seconds_bucket{le=\"1\"} 2\n\
+             duration_seconds_bucket{le=\"+Inf\"} 3\n\
+             duration_seconds_sum 3.8\n\
+             duration_seconds_count 3\n"
+        );
+    }
+}
@@ -3,6 +3,7 @@ use ipc::Semaphore;
 use os_str_bytes::OsStrBytes;
 use path_absolutize::Absolutize;
 use sha2::{Digest, Sha256};
+use std::cell::Cell;
 use std::cmp::max;
 use std::collections::hash_map;
 use std::collections::HashMap;
@@ -14,6 +15,7 @@ use std::path::{Path, PathBuf};
 use std::process::{Command, Output};
 use std::sync::{Arc, RwLock};
 use std::time::Duration;
+use std::vec::IntoIter;
 use tempfile::{NamedTempFile, TempDir};
 use thiserror::Error;
 
@@ -21,6 +23,7 @@ use crate::cache::{Cache, FileHasher};
 use crate::cmd;
 use crate::compiler::CompileInput::{Preprocessed, Source};
 use crate::config::Config;
+use crate::events::{Event, EventLog};
 use crate::io::memstream::MemStream;
 use crate::io::statistic::Statistic;
 use crate::utils::OsStrExt;
@@ -153,6 +156,102 @@ impl Arg {
     }
 }
 
+// Check that arguments received from remote client are safe to run on builder:
+// only compiler arguments from known tables, without files, paths and `denied` prefixes.
+// Parameters from `valued` accept `option=value` only for listed options.
+// Options from `path_maps` only rewrite paths written into output, so their values may contain paths.
+// Returns arguments to run compiler with.
+pub fn check_remote_args(
+    args: &[String],
+    parse_argument: fn(&mut IntoIter<String>) -> Option<Result<Arg, String>>,
+    denied: &[&str],
+    valued: &[(&str, &[&str])],
+    path_maps: &[&str],
+    output_precompiled: bool,
+) -> Result<Vec<String>, String> {
+    let mut result = Vec::new();
+    let mut iter = Vec::from(args).into_iter();
+    loop {
+        let start = args.len() - iter.as_slice().len();
+        let Some(arg) = parse_argument(&mut iter) else {
+            break;
+        };
+        let arg = arg.map_err(|arg| format!("Found unknown command line argument: {arg}"))?;
+        if check_remote_arg(&arg, denied, valued, path_maps, output_precompiled)? {
+            result.extend_from_slice(&args[start..args.len() - iter.as_slice().len()]);
+        }
+    }
+    Ok(result)
+}
+
+// Check single argument, returns false if argument should be dropped.
+fn check_remote_arg(
+    arg: &Arg,
+    denied: &[&str],
+    valued: &[(&str, &[&str])],
+    path_maps: &[&str],
+    output_precompiled: bool,
+) -> Result<bool, String> {
+    let (scope, prefix, name, value) = match arg {
+        Arg::Flag {
+            scope,
+            prefix,
+            name,
+        } => (*scope, *prefix, name.as_str(), ""),
+        Arg::Param {
+            scope,
+            prefix,
+            name,
+            value,
+            ..
+        } => (*scope, *prefix, name.as_str(), value.as_str()),
+        // Precompiled header creation without header name
+        Arg::Output {
+            kind: OutputKind::Marker,
+            file,
+            ..
+        } if output_precompiled && file.as_os_str().is_empty() => {
+            return Ok(true);
+        }
+        Arg::Input { file, .. } | Arg::Output { file, .. } => {
+            return Err(format!("File argument is not allowed: {}", file.display()));
+        }
+    };
+    let full = format!("{name}{value}");
+    if scope == Scope::Preprocessor {
+        // Precompiled header is created from preprocessed data, so preprocessor arguments
+        // (passed locally for consistency with header users) are not needed on builder.
+        if output_precompiled {
+            return Ok(false);
+        }
+        return Err(format!(
+            "Preprocessor argument is not allowed: {prefix}{full}"
+        ));
+    }
+    if denied.iter().any(|v| full.starts_with(v)) {
+        return Err(format!("Argument is not allowed: {prefix}{full}"));
+    }
+    if path_maps
+        .iter()
+        .any(|v| full.strip_prefix(v).is_some_and(|map| map.starts_with('=')))
+    {
+        return Ok(true);
+    }
+    // Option values may reference files without path separators, so only known options are allowed
+    if let Some((option, _)) = value.split_once('=') {
+        if let Some((_, allowed)) = valued.iter().find(|(param, _)| *param == name) {
+            if !allowed.contains(&option) {
+                return Err(format!("Argument is not allowed: {prefix}{full}"));
+            }
+        }
+    }
+    // Macro definitions don't reference files
+    if name != "D" && full.contains(['/', '\\']) {
+        return Err(format!("Argument with path is not allowed: {prefix}{full}"));
+    }
+    Ok(true)
+}
+
 #[derive(Debug, Default)]
 pub struct CommandEnv {
     map: HashMap<String, String>,
@@ -169,8 +268,13 @@ pub struct SharedState {
     pub semaphore: Semaphore,
     pub cache: Cache,
     pub statistic: Statistic,
+    pub events: EventLog,
     pub temp_dir: TempDir,
     use_response_files: bool,
+    // Skip compilation tasks with up-to-date output
+    pub skip_up_to_date: bool,
+    // Command line hashes of compiled outputs, so changed flags make output outdated
+    pub command_records: PathBuf,
 }
 
 #[derive(Default)]
@@ -183,8 +287,11 @@ impl SharedState {
             semaphore,
             cache: Cache::new(config),
             statistic: Statistic::new(),
+            events: EventLog::new(config),
             temp_dir: tempfile::Builder::new().prefix("octobuild").tempdir()?,
             use_response_files: config.use_response_files,
+            skip_up_to_date: config.skip_up_to_date,
+            command_records: config.cache.join("commands"),
         })
     }
 
@@ -340,33 +447,62 @@ pub struct OutputInfo {
     pub stderr: Vec<u8>,
 }
 
+#[derive(Clone, Copy)]
+enum OutputTarget {
+    Stdout,
+    Stderr,
+}
+
 pub struct BuildTaskResult {
     pub output: crate::Result<OutputInfo>,
     pub duration: Duration,
+    // Task was skipped because its output is up to date
+    pub skipped: bool,
 }
 
 impl BuildTaskResult {
     pub fn print_output(&self) -> crate::Result<()> {
+        Ok(self.format_output(|target, data| match target {
+            OutputTarget::Stdout => stdout().write_all(data),
+            OutputTarget::Stderr => stderr().write_all(data),
+        })?)
+    }
+
+    // Write task status, stdout and stderr as single block.
+    pub fn write_output(&self, out: &mut impl Write) -> std::io::Result<()> {
+        self.format_output(|_, data| out.write_all(data))
+    }
+
+    // Format task status and output, passing every part to its target stream.
+    fn format_output(
+        &self,
+        mut write: impl FnMut(OutputTarget, &[u8]) -> std::io::Result<()>,
+    ) -> std::io::Result<()> {
         match &self.output {
             Ok(output) => {
                 if !output.success() {
-                    writeln!(
-                        stderr(),
-                        "ERROR: Task failed with exit code: {}",
+                    let message = format!(
+                        "ERROR: Task failed with exit code: {}\n",
                         output
                             .status
                             .map_or_else(|| "unknown".to_string(), |v| v.to_string())
-                    )?;
+                    );
+                    write(OutputTarget::Stderr, message.as_bytes())?;
                 }
-                stdout().write_all(&output.stdout)?;
-                stderr().write_all(&output.stderr)?;
+                write(OutputTarget::Stdout, &output.stdout)?;
+                write(OutputTarget::Stderr, &output.stderr)?;
             }
             Err(e) => {
-                writeln!(stderr(), "ERROR: {e}")?;
+                write(OutputTarget::Stderr, format!("ERROR: {e}\n").as_bytes())?;
             }
         }
         Ok(())
     }
+
+    #[must_use]
+    pub fn success(&self) -> bool {
+        matches!(&self.output, Ok(output) if output.success())
+    }
 }
 
 impl OutputInfo {
@@ -447,9 +583,19 @@ pub struct CompilationArgs {
     pub args: Vec<Arg>,
     pub pch_usage: PCHUsage,
     pub deps_file: Option<PathBuf>,
+    pub deps_format: DepsFormat,
     pub run_second_cpp: bool,
 }
 
+// Format of dependency file written by compiler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DepsFormat {
+    // Makefile rule (`-MD`/`-MF`)
+    Make,
+    // JSON (`/sourceDependencies`)
+    Json,
+}
+
 #[derive(Clone, Debug)]
 pub struct CompilationTask {
     // Compilation  arguments.
@@ -469,6 +615,8 @@ pub struct SourceInput {
 
 pub enum CompileInput {
     Preprocessed(CompilerOutput),
+    // Preprocessed data stored in file (received by builder)
+    PreprocessedFile(PathBuf),
     Source(SourceInput),
 }
 
@@ -480,6 +628,8 @@ pub struct CompileStep {
     pub pch_usage: PCHUsage,
     pub input: CompileInput,
     pub run_second_cpp: bool,
+    // Run compiler without inherited environment variables (for reproducible output).
+    pub clear_env: bool,
 }
 
 impl CompileStep {
@@ -498,8 +648,18 @@ impl CompileStep {
                 Preprocessed(preprocessed)
             },
             run_second_cpp: task.shared.run_second_cpp,
+            // Original source may depend on environment (CPATH, etc.), preprocessed data doesn't
+            clear_env: !task.shared.run_second_cpp,
         }
     }
+
+    // Main output file: object file or precompiled header (if task produces only it).
+    #[must_use]
+    pub fn output(&self) -> Option<&Path> {
+        self.output_object
+            .as_deref()
+            .or_else(|| self.pch_usage.get_out_abs().map(PathBuf::as_path))
+    }
 }
 
 pub enum CompilerOutput {
@@ -508,7 +668,7 @@ pub enum CompilerOutput {
 }
 
 impl CompilerOutput {
-    pub fn copy<W: Write>(&self, writer: &mut W) -> std::io::Result<usize> {
+    pub fn copy<W: Write + ?Sized>(&self, writer: &mut W) -> std::io::Result<usize> {
         match &self {
             CompilerOutput::MemSteam(v) => v.copy(writer),
             CompilerOutput::Vec(v) => {
@@ -569,6 +729,12 @@ pub trait Toolchain: Send + Sync {
         task: &CompilationTask,
         preprocessed: CompilerOutput,
     ) -> crate::Result<CompileStep>;
+    // Check compile step arguments received from remote client, returns arguments to run.
+    fn check_remote_args(
+        &self,
+        args: &[String],
+        output_precompiled: bool,
+    ) -> Result<Vec<String>, String>;
 
     // Compile preprocessed file.
     fn run_compile(&self, state: &SharedState, task: CompileStep) -> crate::Result<OutputInfo>;
@@ -640,12 +806,21 @@ pub trait Toolchain: Send + Sync {
         }
 
         // Try to get files from cache or run
-        state.cache.run_file_cached(
+        let output_path = step.output().map(Path::to_path_buf);
+        let executed = Cell::new(false);
+        let output = state.cache.run_file_cached(
             &state.statistic,
             &hex::encode(hasher.finalize()),
             outputs,
-            || -> crate::Result<OutputInfo> { self.run_compile(state, step) },
-        )
+            || -> crate::Result<OutputInfo> {
+                executed.set(true);
+                self.run_compile(state, step)
+            },
+        )?;
+        if let (false, Some(path)) = (executed.get(), &output_path) {
+            state.events.send(&Event::CacheHit { output: path });
+        }
+        Ok(output)
     }
 }
 
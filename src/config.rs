@@ -19,11 +19,35 @@ pub struct Config {
     pub cache_mode: CacheMode,
     pub cache_limit_mb: u64,
     pub cache_compression_level: u32,
+    // Shared secret for cluster requests signing (never printed)
+    #[serde(skip_serializing)]
+    pub cluster_token: Option<String>,
+    // PEM certificate chain and private key for coordinator and builder HTTPS servers
+    pub cluster_tls_cert: Option<PathBuf>,
+    pub cluster_tls_key: Option<PathBuf>,
+    // Additional PEM CA certificate trusted by cluster HTTPS clients
+    pub cluster_tls_ca: Option<PathBuf>,
+    pub grouped_output: bool,
     pub coordinator: Option<url::Url>,
     pub coordinator_bind: SocketAddr,
+    // Multicast group for builder announcements when cluster has no coordinator
+    pub cluster_discovery: Option<SocketAddrV4>,
+    // Local interface address for multicast discovery
+    pub cluster_discovery_interface: Ipv4Addr,
+    pub event_log: Option<String>,
     pub helper_bind: SocketAddr,
+    pub builder_queue_limit: usize,
+    // Builder labels reported to coordinator and clients
+    pub builder_labels: Vec<String>,
+    // Labels required from builders for remote compilation, `!label` excludes builders with label
+    pub builder_selector: Vec<String>,
+    pub builder_pch_limit_mb: u64,
+    pub builder_pch_max_age_hours: u64,
+    // Max time to wait for running tasks on builder stop or reload
+    pub builder_drain_timeout_secs: u64,
     pub process_limit: usize,
     pub run_second_cpp: bool,
+    pub skip_up_to_date: bool,
     pub use_response_files: bool,
 }
 
@@ -46,11 +70,26 @@ impl Default for Config {
             cache_mode: CacheMode::ReadWrite,
             cache_limit_mb: 64 * 1024,
             cache_compression_level: 1,
+            cluster_token: None,
+            cluster_tls_cert: None,
+            cluster_tls_key: None,
+            cluster_tls_ca: None,
+            grouped_output: false,
             coordinator: None,
             coordinator_bind: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 3000)),
+            cluster_discovery: None,
+            cluster_discovery_interface: Ipv4Addr::UNSPECIFIED,
+            event_log: None,
             helper_bind: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0)),
             process_limit: num_cpus::get(),
+            builder_queue_limit: num_cpus::get(),
+            builder_labels: Vec::new(),
+            builder_selector: Vec::new(),
+            builder_pch_limit_mb: 8 * 1024,
+            builder_pch_max_age_hours: 24,
+            builder_drain_timeout_secs: 300,
             run_second_cpp: true,
+            skip_up_to_date: false,
             use_response_files: DEFAULT_USE_RESPONSE_FILES,
         }
     }
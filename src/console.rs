@@ -0,0 +1,77 @@
+use std::io::{IsTerminal, Write};
+
+/// Sticky status line at the bottom of terminal output.
+///
+/// Regular output must be printed only after `clear`, so the status line
+/// always stays below it. When stdout is not a terminal, nothing is shown.
+pub struct StatusLine {
+    enabled: bool,
+    // Width of currently shown status text
+    shown: usize,
+}
+
+impl Default for StatusLine {
+    fn default() -> Self {
+        StatusLine {
+            enabled: std::io::stdout().is_terminal(),
+            shown: 0,
+        }
+    }
+}
+
+impl StatusLine {
+    #[must_use]
+    pub fn new() -> Self {
+        StatusLine::default()
+    }
+
+    #[must_use]
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn clear(&mut self, out: &mut impl Write) -> std::io::Result<()> {
+        if self.shown > 0 {
+            write!(out, "\r{}\r", " ".repeat(self.shown))?;
+            out.flush()?;
+            self.shown = 0;
+        }
+        Ok(())
+    }
+
+    pub fn show(&mut self, out: &mut impl Write, text: &str) -> std::io::Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        self.clear(out)?;
+        // Keep last column free: writing into it moves cursor to the next line on some terminals
+        let width = terminal_width().unwrap_or(80).saturating_sub(1);
+        let text: String = text.chars().take(width).collect();
+        write!(out, "{text}")?;
+        out.flush()?;
+        self.shown = text.chars().count();
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn terminal_width() -> Option<usize> {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } != 0 {
+        return None;
+    }
+    Some(usize::from(size.ws_col)).filter(|width| *width > 0)
+}
+
+#[cfg(windows)]
+fn terminal_width() -> Option<usize> {
+    use winapi::um::processenv::GetStdHandle;
+    use winapi::um::winbase::STD_OUTPUT_HANDLE;
+    use winapi::um::wincon::{GetConsoleScreenBufferInfo, CONSOLE_SCREEN_BUFFER_INFO};
+
+    let mut info: CONSOLE_SCREEN_BUFFER_INFO = unsafe { std::mem::zeroed() };
+    if unsafe { GetConsoleScreenBufferInfo(GetStdHandle(STD_OUTPUT_HANDLE), &mut info) } == 0 {
+        return None;
+    }
+    usize::try_from(info.srWindow.Right - info.srWindow.Left + 1).ok()
+}
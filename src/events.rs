@@ -0,0 +1,162 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::warn;
+
+use crate::config::Config;
+
+// Unix domain socket prefix for event log target.
+const UNIX_PREFIX: &str = "unix:";
+
+#[derive(serde::Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    TaskStarted {
+        worker: usize,
+        title: &'a str,
+        output: Option<&'a Path>,
+    },
+    TaskFinished {
+        worker: usize,
+        title: &'a str,
+        output: Option<&'a Path>,
+        success: bool,
+        duration_ms: u128,
+    },
+    CacheHit {
+        output: &'a Path,
+    },
+    Remote {
+        output: Option<&'a Path>,
+        builder: &'a str,
+    },
+}
+
+#[derive(serde::Serialize)]
+struct Record<'a> {
+    // Milliseconds since UNIX epoch
+    time: u128,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+/// Build events as newline-delimited JSON for external tools (IDE integrations, etc).
+#[derive(Default)]
+pub struct EventLog {
+    writer: Mutex<Option<Box<dyn Write + Send>>>,
+}
+
+impl EventLog {
+    #[must_use]
+    pub fn new(config: &Config) -> Self {
+        let Some(target) = &config.event_log else {
+            return EventLog::default();
+        };
+        match open(target) {
+            Ok(writer) => EventLog::with_writer(writer),
+            Err(e) => {
+                warn!("Can't open event log {target}: {e}");
+                EventLog::default()
+            }
+        }
+    }
+
+    #[must_use]
+    pub fn with_writer(writer: Box<dyn Write + Send>) -> Self {
+        EventLog {
+            writer: Mutex::new(Some(writer)),
+        }
+    }
+
+    pub fn send(&self, event: &Event) {
+        let mut writer = self.writer.lock().unwrap();
+        let Some(out) = writer.as_mut() else {
+            return;
+        };
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO)
+            .as_millis();
+        let mut line = serde_json::to_vec(&Record { time, event }).unwrap();
+        line.push(b'\n');
+        if let Err(e) = out.write_all(&line).and_then(|()| out.flush()) {
+            // Event consumer has gone away, don't slow down the build with further attempts.
+            warn!("Can't write event log, disabling it: {e}");
+            *writer = None;
+        }
+    }
+}
+
+fn open(target: &str) -> std::io::Result<Box<dyn Write + Send>> {
+    if let Some(path) = target.strip_prefix(UNIX_PREFIX) {
+        return open_socket(Path::new(path));
+    }
+    Ok(Box::new(
+        OpenOptions::new().create(true).append(true).open(target)?,
+    ))
+}
+
+#[cfg(unix)]
+fn open_socket(path: &Path) -> std::io::Result<Box<dyn Write + Send>> {
+    Ok(Box::new(std::os::unix::net::UnixStream::connect(path)?))
+}
+
+#[cfg(not(unix))]
+fn open_socket(_: &Path) -> std::io::Result<Box<dyn Write + Send>> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Unix domain sockets are not supported on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+
+    use crate::events::{Event, EventLog};
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_event_log_ndjson() {
+        let buffer = Buffer::default();
+        let log = EventLog::with_writer(Box::new(buffer.clone()));
+        log.send(&Event::TaskStarted {
+            worker: 1,
+            title: "foo.cpp",
+            output: None,
+        });
+        log.send(&Event::CacheHit {
+            output: Path::new("foo.o"),
+        });
+
+        let data = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<serde_json::Value> = data
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["event"], "task_started");
+        assert_eq!(lines[0]["title"], "foo.cpp");
+        assert_eq!(lines[0]["worker"], 1);
+        assert_eq!(lines[1]["event"], "cache_hit");
+        assert_eq!(lines[1]["output"], "foo.o");
+        assert!(lines[1]["time"].is_u64());
+    }
+}
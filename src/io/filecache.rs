@@ -123,6 +123,10 @@ impl FileCache {
         foreach_cache_file(
             &self.cache_dir,
             &mut (|path: PathBuf, metadata: fs::Metadata| -> crate::Result<()> {
+                // Cache directory may contain other files (for example, builder precompiled headers)
+                if !path.to_string_lossy().ends_with(SUFFIX) {
+                    return Ok(());
+                }
                 files.insert(CacheFile {
                     path,
                     size: metadata.len(),
@@ -0,0 +1,216 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bincode::{Decode, Encode};
+use log::warn;
+
+use crate::config::Config;
+use crate::io::tempfile::TempFile;
+
+const HISTORY_FILE: &str = "history.bin";
+const HISTORY_VERSION: u32 = 1;
+// Entries not updated for this time are dropped on save.
+const HISTORY_MAX_AGE: Duration = Duration::from_secs(30 * 24 * 3600);
+// Max count of stored entries, least recently updated ones are dropped first.
+const HISTORY_LIMIT: usize = 100_000;
+
+#[derive(Decode, Encode, Clone, Copy)]
+struct HistoryEntry {
+    duration: Duration,
+    // Unix time of last update, in seconds
+    updated: u64,
+}
+
+#[derive(Decode, Encode)]
+struct HistoryData {
+    version: u32,
+    entries: HashMap<String, HistoryEntry>,
+}
+
+/// Durations of previously executed build tasks keyed by stable task identity.
+pub struct TaskHistory {
+    path: PathBuf,
+    entries: RwLock<HashMap<String, HistoryEntry>>,
+    modified: AtomicBool,
+}
+
+impl TaskHistory {
+    #[must_use]
+    pub fn new(config: &Config) -> Self {
+        TaskHistory::load(&config.cache.join(HISTORY_FILE))
+    }
+
+    #[must_use]
+    pub fn load(path: &Path) -> Self {
+        TaskHistory {
+            path: path.to_path_buf(),
+            entries: RwLock::new(read_history(path)),
+            modified: AtomicBool::new(false),
+        }
+    }
+
+    #[must_use]
+    pub fn get(&self, identity: &str) -> Option<Duration> {
+        self.entries
+            .read()
+            .unwrap()
+            .get(identity)
+            .map(|entry| entry.duration)
+    }
+
+    pub fn update(&self, identity: String, duration: Duration) {
+        self.entries.write().unwrap().insert(
+            identity,
+            HistoryEntry {
+                duration,
+                updated: unix_time(SystemTime::now()),
+            },
+        );
+        self.modified.store(true, Ordering::Relaxed);
+    }
+
+    // Merge updated durations into history file, so concurrent builds don't lose each other's updates.
+    pub fn save(&self) -> crate::Result<()> {
+        if !self.modified.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        let dir = self.path.parent().unwrap_or_else(|| Path::new("."));
+        fs::create_dir_all(dir)?;
+
+        let mut entries = read_history(&self.path);
+        for (identity, entry) in self.entries.read().unwrap().iter() {
+            if entries
+                .get(identity)
+                .is_none_or(|stored| stored.updated < entry.updated)
+            {
+                entries.insert(identity.clone(), *entry);
+            }
+        }
+        prune(&mut entries, SystemTime::now());
+
+        // Write into temporary file first, so concurrent builds never see partially written history.
+        let temporary = TempFile::new_in(dir, ".tmp");
+        {
+            let mut writer = BufWriter::new(File::create(temporary.path())?);
+            bincode::encode_into_std_write(
+                &HistoryData {
+                    version: HISTORY_VERSION,
+                    entries,
+                },
+                &mut writer,
+                bincode::config::standard(),
+            )?;
+            writer.flush()?;
+        }
+        fs::rename(temporary.path(), &self.path)?;
+        Ok(())
+    }
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+// Drop expired entries and least recently updated entries over limit.
+fn prune(entries: &mut HashMap<String, HistoryEntry>, now: SystemTime) {
+    let expired = unix_time(now).saturating_sub(HISTORY_MAX_AGE.as_secs());
+    entries.retain(|_, entry| entry.updated >= expired);
+    if entries.len() > HISTORY_LIMIT {
+        let mut sorted: Vec<(String, HistoryEntry)> = entries.drain().collect();
+        sorted.sort_unstable_by_key(|(_, entry)| Reverse(entry.updated));
+        sorted.truncate(HISTORY_LIMIT);
+        entries.extend(sorted);
+    }
+}
+
+fn read_history(path: &Path) -> HashMap<String, HistoryEntry> {
+    match decode_history(path) {
+        Ok(data) if data.version == HISTORY_VERSION => data.entries,
+        Ok(_) => HashMap::new(),
+        Err(e) => {
+            if path.exists() {
+                warn!("Can't read task history {}: {e}", path.display());
+            }
+            HashMap::new()
+        }
+    }
+}
+
+fn decode_history(path: &Path) -> crate::Result<HistoryData> {
+    let mut reader = BufReader::new(File::open(path)?);
+    Ok(bincode::decode_from_std_read(
+        &mut reader,
+        bincode::config::standard(),
+    )?)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::time::{Duration, SystemTime};
+
+    use crate::io::history::{
+        prune, unix_time, HistoryEntry, TaskHistory, HISTORY_LIMIT, HISTORY_MAX_AGE,
+    };
+
+    #[test]
+    fn test_history_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.bin");
+
+        let history = TaskHistory::load(&path);
+        assert_eq!(history.get("foo"), None);
+        history.update("foo".to_string(), Duration::from_millis(1500));
+        history.save().unwrap();
+
+        let history = TaskHistory::load(&path);
+        assert_eq!(history.get("foo"), Some(Duration::from_millis(1500)));
+        assert_eq!(history.get("bar"), None);
+    }
+
+    #[test]
+    fn test_history_merge() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.bin");
+
+        // Two builds started with the same history
+        let first = TaskHistory::load(&path);
+        let second = TaskHistory::load(&path);
+        first.update("foo".to_string(), Duration::from_secs(1));
+        first.save().unwrap();
+        second.update("bar".to_string(), Duration::from_secs(2));
+        second.save().unwrap();
+
+        let history = TaskHistory::load(&path);
+        assert_eq!(history.get("foo"), Some(Duration::from_secs(1)));
+        assert_eq!(history.get("bar"), Some(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn test_history_prune() {
+        let now = SystemTime::now();
+        let entry = |age_secs: u64| HistoryEntry {
+            duration: Duration::from_secs(1),
+            updated: unix_time(now) - age_secs,
+        };
+        let mut entries: HashMap<String, HistoryEntry> = (0..HISTORY_LIMIT as u64 + 10)
+            .map(|age| (format!("task{age}"), entry(age)))
+            .collect();
+        entries.insert("expired".to_string(), entry(HISTORY_MAX_AGE.as_secs() + 1));
+
+        prune(&mut entries, now);
+        assert_eq!(entries.len(), HISTORY_LIMIT);
+        assert!(entries.contains_key("task0"));
+        assert!(!entries.contains_key(&format!("task{HISTORY_LIMIT}")));
+        assert!(!entries.contains_key("expired"));
+    }
+}
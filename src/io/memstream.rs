@@ -61,7 +61,7 @@ impl MemStream {
         }
     }
 
-    pub fn copy<W: Write>(&self, writer: &mut W) -> Result<usize> {
+    pub fn copy<W: Write + ?Sized>(&self, writer: &mut W) -> Result<usize> {
         for block in self.iter() {
             writer.write_all(block)?;
         }
@@ -0,0 +1,112 @@
+use std::cmp::min;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+use crossbeam_channel::{bounded, Receiver, Sender};
+
+const CHUNK_SIZE: usize = 0x10000;
+
+// Writing end of in-memory pipe.
+pub struct PipeWriter {
+    sender: Sender<Vec<u8>>,
+    buffer: Vec<u8>,
+}
+
+// Reading end of in-memory pipe, reaches end of stream when writer is dropped.
+pub struct PipeReader {
+    receiver: Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    offset: usize,
+}
+
+// Create in-memory pipe, which buffers up to `capacity` chunks between writer and reader threads.
+#[must_use]
+pub fn pipe(capacity: usize) -> (PipeWriter, PipeReader) {
+    let (sender, receiver) = bounded(capacity);
+    (
+        PipeWriter {
+            sender,
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+        },
+        PipeReader {
+            receiver,
+            chunk: Vec::new(),
+            offset: 0,
+        },
+    )
+}
+
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let size = min(buf.len(), CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..size]);
+        if self.buffer.len() == CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::replace(&mut self.buffer, Vec::with_capacity(CHUNK_SIZE));
+        self.sender
+            .send(chunk)
+            .map_err(|_| Error::new(ErrorKind::BrokenPipe, "Pipe reader is closed"))
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        drop(self.flush());
+    }
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        while self.offset == self.chunk.len() {
+            match self.receiver.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.offset = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+        let size = min(buf.len(), self.chunk.len() - self.offset);
+        buf[..size].copy_from_slice(&self.chunk[self.offset..self.offset + size]);
+        self.offset += size;
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::thread;
+
+    use crate::io::pipe::pipe;
+
+    #[test]
+    fn test_pipe() {
+        let expected: Vec<u8> = (0..300_000).map(|i| (i % 251) as u8).collect();
+        let (mut writer, mut reader) = pipe(2);
+        let data = expected.clone();
+        let producer = thread::spawn(move || {
+            for block in data.chunks(1000) {
+                writer.write_all(block).unwrap();
+            }
+        });
+        let mut actual = Vec::new();
+        reader.read_to_end(&mut actual).unwrap();
+        producer.join().unwrap();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_pipe_closed() {
+        let (mut writer, reader) = pipe(1);
+        drop(reader);
+        assert!(writer.write_all(&[0; 0x20000]).is_err());
+    }
+}
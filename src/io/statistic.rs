@@ -10,6 +10,16 @@ pub struct Statistic {
     pub miss_count: AtomicUsize,
     pub miss_bytes: AtomicUsize,
     pub remote_count: AtomicUsize,
+    // Count of remote compilation retries on another builder
+    pub retry_count: AtomicUsize,
+    // Count of tasks skipped because of up-to-date outputs
+    pub skip_count: AtomicUsize,
+    // Count of remote tasks in progress
+    pub remote_active: AtomicUsize,
+}
+
+pub struct RemoteTaskGuard<'a> {
+    statistic: &'a Statistic,
 }
 
 impl fmt::Display for Statistic {
@@ -19,14 +29,18 @@ impl fmt::Display for Statistic {
         let miss_count = self.miss_count.load(Ordering::Relaxed);
         let miss_bytes = self.miss_bytes.load(Ordering::Relaxed);
         let remote_count = self.remote_count.load(Ordering::Relaxed);
+        let retry_count = self.retry_count.load(Ordering::Relaxed);
+        let skip_count = self.skip_count.load(Ordering::Relaxed);
         let total_count = hit_count + miss_count;
         write!(
             f,
-            "Cache statistic: hit {} of {} ({} %), remote {}, read {}, write {}, total {}",
+            "Cache statistic: hit {} of {} ({} %), remote {}, retries {}, skipped {}, read {}, write {}, total {}",
             hit_count,
             total_count,
             hit_count * 100 / max(total_count, 1),
             remote_count,
+            retry_count,
+            skip_count,
             hit_bytes,
             miss_bytes,
             hit_bytes + miss_bytes,
@@ -53,4 +67,32 @@ impl Statistic {
     pub fn inc_remote(&self) {
         self.remote_count.fetch_add(1, Ordering::Release);
     }
+
+    pub fn inc_retry(&self) {
+        self.retry_count.fetch_add(1, Ordering::Release);
+    }
+
+    pub fn inc_skip(&self) {
+        self.skip_count.fetch_add(1, Ordering::Release);
+    }
+
+    #[must_use]
+    pub fn hit_percent(&self) -> usize {
+        let hit_count = self.hit_count.load(Ordering::Relaxed);
+        let miss_count = self.miss_count.load(Ordering::Relaxed);
+        hit_count * 100 / max(hit_count + miss_count, 1)
+    }
+
+    // Mark remote task as in progress until the guard is dropped.
+    #[must_use]
+    pub fn remote_task(&self) -> RemoteTaskGuard<'_> {
+        self.remote_active.fetch_add(1, Ordering::Release);
+        RemoteTaskGuard { statistic: self }
+    }
+}
+
+impl Drop for RemoteTaskGuard<'_> {
+    fn drop(&mut self) {
+        self.statistic.remote_active.fetch_sub(1, Ordering::Release);
+    }
 }
@@ -9,13 +9,18 @@ use crate::vs::postprocess::PostprocessError;
 pub mod cache;
 
 pub mod cluster {
+    pub mod auth;
     pub mod builder;
     pub mod client;
     pub mod common;
+    pub mod discovery;
+    pub mod metrics;
 }
 
 pub mod compiler;
 pub mod config;
+pub mod console;
+pub mod events;
 pub mod lazy;
 pub mod utils;
 pub mod version;
@@ -24,8 +29,10 @@ pub mod io {
     pub mod binary;
     pub mod counter;
     pub mod filecache;
+    pub mod history;
     pub mod memcache;
     pub mod memstream;
+    pub mod pipe;
     pub mod statistic;
     pub mod tempfile;
 }
@@ -36,12 +43,14 @@ pub mod xg {
 
 pub mod vs {
     pub mod compiler;
+    pub mod depfile;
     pub mod postprocess;
     pub mod prepare;
 }
 
 pub mod clang {
     pub mod compiler;
+    pub mod depfile;
     pub mod prepare;
 }
 
@@ -66,7 +66,7 @@ where
     C: Compiler,
 {
     let command_info = CommandInfo::simple(PathBuf::from(exec));
-    let remote = RemoteCompiler::new(&config.coordinator, compiler);
+    let remote = RemoteCompiler::new(config, compiler)?;
     let args = env::args().skip(1).collect();
     let actions = BuildAction::create_tasks(
         &remote,
@@ -83,7 +83,14 @@ where
             action,
         }));
     }
-    let result = execute_graph(state, build_graph, config.process_limit, print_task_result);
+    let result = execute_graph(
+        state,
+        build_graph,
+        config.process_limit,
+        None,
+        print_task_result,
+        |_| Ok(()),
+    );
     writeln!(stdout(), "{}", state.statistic)?;
     result
 }
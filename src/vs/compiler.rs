@@ -1,5 +1,5 @@
 use crate::cmd;
-use crate::compiler::CompileInput::{Preprocessed, Source};
+use crate::compiler::CompileInput::{Preprocessed, PreprocessedFile, Source};
 use crate::compiler::{
     Arg, CommandInfo, CompilationTask, CompileStep, Compiler, CompilerOutput, OutputInfo, PCHUsage,
     ParamForm, PreprocessResult, Scope, SharedState, Toolchain, ToolchainHolder,
@@ -265,6 +265,14 @@ impl Toolchain for VsToolchain {
         Ok(CompileStep::new(task, preprocessed, args))
     }
 
+    fn check_remote_args(
+        &self,
+        args: &[String],
+        output_precompiled: bool,
+    ) -> Result<Vec<String>, String> {
+        super::prepare::check_remote_args(args, output_precompiled)
+    }
+
     fn run_compile(&self, state: &SharedState, task: CompileStep) -> crate::Result<OutputInfo> {
         let (output_path, temp_output) = match task.output_object {
             Some(v) => (v, None),
@@ -301,6 +309,7 @@ impl Toolchain for VsToolchain {
                 preprocessed.copy(&mut File::create(input_temp.path())?)?;
                 (input_temp.path().to_path_buf(), Some(input_temp), None)
             }
+            PreprocessedFile(path) => (path.clone(), None, None),
             Source(source) => {
                 if let Some(dir) = &source.current_dir {
                     (source.path.clone(), None, Some(dir.as_path()))
@@ -0,0 +1,53 @@
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+struct SourceDependencies {
+    #[serde(rename = "Data")]
+    data: SourceDependenciesData,
+}
+
+#[derive(Deserialize)]
+struct SourceDependenciesData {
+    #[serde(rename = "Includes", default)]
+    includes: Vec<PathBuf>,
+}
+
+// Parse included files from JSON dependency file (as produced by `/sourceDependencies`).
+#[must_use]
+pub fn parse_source_dependencies(data: &str) -> Option<Vec<PathBuf>> {
+    serde_json::from_str::<SourceDependencies>(data)
+        .ok()
+        .map(|dependencies| dependencies.data.includes)
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use crate::vs::depfile::parse_source_dependencies;
+
+    #[test]
+    fn test_parse_source_dependencies() {
+        assert_eq!(
+            parse_source_dependencies(
+                r#"{
+                    "Version": "1.2",
+                    "Data": {
+                        "Source": "c:\\project\\src.cpp",
+                        "ProvidedModule": "",
+                        "Includes": ["c:\\project\\a.h", "c:\\sdk\\include\\b.h"],
+                        "ImportedModules": [],
+                        "ImportedHeaderUnits": []
+                    }
+                }"#
+            ),
+            Some(vec![
+                PathBuf::from("c:\\project\\a.h"),
+                PathBuf::from("c:\\sdk\\include\\b.h"),
+            ])
+        );
+        assert_eq!(parse_source_dependencies("src.o: src.cpp"), None);
+    }
+}
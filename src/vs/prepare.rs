@@ -1,6 +1,6 @@
 use crate::compiler::{
-    Arg, CommandInfo, CompilationArgs, CompilationTask, InputKind, OutputKind, PCHArgs, PCHUsage,
-    ParamForm, Scope,
+    check_remote_args as check_remote_args_parsed, Arg, CommandInfo, CompilationArgs,
+    CompilationTask, DepsFormat, InputKind, OutputKind, PCHArgs, PCHUsage, ParamForm, Scope,
 };
 use crate::utils::{expand_response_files, find_param, ParamValue};
 use std::ffi::OsString;
@@ -126,11 +126,29 @@ pub fn create_tasks(
             )));
         }
     };
+    // Dependency file
+    let deps_file = match find_param(&parsed_args, |arg: &Arg| -> Option<PathBuf> {
+        match arg {
+            Arg::Param { name, value, .. } if *name == "sourceDependencies" && value != "-" => {
+                Some(PathBuf::from(value))
+            }
+            _ => None,
+        }
+    }) {
+        ParamValue::None => None,
+        ParamValue::Single(v) => Some(command.absolutize(&v)?),
+        ParamValue::Many(v) => {
+            return Err(crate::Error::from(format!(
+                "Found too many dependency files: {v:?}"
+            )));
+        }
+    };
     let shared = Arc::new(CompilationArgs {
         args: parsed_args,
         pch_usage,
         command,
-        deps_file: None,
+        deps_file,
+        deps_format: DepsFormat::Json,
         run_second_cpp,
     });
     input_sources
@@ -195,6 +213,20 @@ fn get_output_object(
     Ok(result)
 }
 
+// Arguments, which allow to load code or read files on builder.
+const DENIED_REMOTE_ARGS: &[&str] = &["analyze:plugin", "analyze:log", "analyze:ruleset"];
+
+pub fn check_remote_args(args: &[String], output_precompiled: bool) -> Result<Vec<String>, String> {
+    check_remote_args_parsed(
+        args,
+        parse_argument,
+        DENIED_REMOTE_ARGS,
+        &[],
+        &[],
+        output_precompiled,
+    )
+}
+
 fn parse_arguments(args: Vec<String>) -> Result<Vec<Arg>, String> {
     let mut result: Vec<Arg> = Vec::new();
     let mut errors: Vec<String> = Vec::new();
@@ -260,13 +292,17 @@ fn parse_argument(iter: &mut IntoIter<String>) -> Option<Result<Arg, String>> {
 
                     "X" => Ok(Arg::flag(Scope::Preprocessor, "/", flag)),
 
-                    s if s.starts_with('T') => Ok(Arg::param_ext(
+                    "TC" | "TP" => Ok(Arg::param_ext(
                         Scope::Ignore,
                         "/",
                         "T",
-                        &s[1..],
+                        &flag[1..],
                         ParamForm::Smushed,
                     )),
+                    s if s.starts_with("Tc") || s.starts_with("Tp") => Ok(Arg::Input {
+                        kind: InputKind::Source,
+                        file: PathBuf::from(&s[2..]),
+                    }),
                     s if s.starts_with('O') => Ok(Arg::flag(Scope::Shared, "/", flag)),
                     s if s.starts_with('G') => Ok(Arg::flag(Scope::Shared, "/", flag)),
                     s if s.starts_with("RTC") => Ok(Arg::flag(Scope::Shared, "/", flag)),
@@ -402,3 +438,23 @@ fn test_parse_argument() {
         ]
     )
 }
+
+#[test]
+fn test_check_remote_args() {
+    let split = |args: &str| -> Vec<String> { args.split(' ').map(|x| x.to_string()).collect() };
+    let check = |args: &str| check_remote_args(&split(args), false);
+    assert!(check("/nologo /TP /O2 /EHsc /DPLATFORM_HEADER=\"Windows/Platform.h\" /W4").is_ok());
+    assert!(check("/nologo /TP /B1 evil.dll").is_err());
+    assert!(check("/nologo /TP /analyze:pluginevil.dll").is_err());
+    assert!(check("/nologo /TP /Fo\\\\host\\share\\evil.obj").is_err());
+    assert!(check("/nologo /TP /FIevil.h").is_err());
+    assert!(check("/nologo /TP /Yc").is_err());
+    assert!(check("/nologo /Tcevil.c").is_err());
+    assert!(check("/nologo /Tpevil.cpp").is_err());
+    // Precompiled header creation
+    assert_eq!(
+        check_remote_args(&split("/nologo /TP /Yc /I C:\\inc /O2"), true).unwrap(),
+        split("/nologo /TP /Yc /O2")
+    );
+    assert!(check_remote_args(&split("/nologo /TP /Ycsample.h"), true).is_err());
+}
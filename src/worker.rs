@@ -1,17 +1,24 @@
-use log::error;
+use log::{error, warn};
 use std::borrow::Cow;
 use std::cmp::{max, min};
+use std::fs;
 use std::io::Write;
-use std::sync::Arc;
-use std::time::Instant;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use petgraph::graph::NodeIndex;
 use petgraph::{EdgeDirection, Graph};
+use sha2::{Digest, Sha256};
 
+use crate::clang::depfile::parse_prerequisites;
 use crate::compiler::{
-    BuildTaskResult, CommandArgs, CommandInfo, CompilationTask, Compiler, OutputInfo, SharedState,
-    Toolchain,
+    BuildTaskResult, CommandArgs, CommandInfo, CompilationTask, Compiler, DepsFormat, OutputInfo,
+    SharedState, Toolchain,
 };
+use crate::events::Event;
+use crate::io::history::TaskHistory;
+use crate::vs::depfile::parse_source_dependencies;
 
 pub type BuildGraph = Graph<Arc<BuildTask>, ()>;
 
@@ -23,6 +30,7 @@ pub struct BuildTask {
 impl BuildTask {
     fn execute(&self, state: &SharedState) -> BuildTaskResult {
         let start_time = Instant::now();
+        let mut skipped = false;
         let output = match &self.action {
             BuildAction::Empty => Ok(OutputInfo {
                 status: Some(0),
@@ -35,15 +43,100 @@ impl BuildTask {
                 let output = command.output()?;
                 Ok(OutputInfo::new(output))
             }),
-            BuildAction::Compilation(toolchain, task) => toolchain.compile_task(state, task),
+            BuildAction::Compilation(_, task)
+                if state.skip_up_to_date && is_up_to_date(state, task) =>
+            {
+                state.statistic.inc_skip();
+                skipped = true;
+                Ok(OutputInfo {
+                    status: Some(0),
+                    stderr: Vec::new(),
+                    stdout: Vec::new(),
+                })
+            }
+            BuildAction::Compilation(toolchain, task) => {
+                let output = toolchain.compile_task(state, task);
+                if state.skip_up_to_date && output.as_ref().is_ok_and(OutputInfo::success) {
+                    if let Err(e) = save_command_hash(state, task) {
+                        warn!("Can't save command line of {}: {e}", self.title);
+                    }
+                }
+                output
+            }
         };
         BuildTaskResult {
             output,
             duration: Instant::now().duration_since(start_time),
+            skipped,
         }
     }
 }
 
+// Check that output object is newer than source file and all dependencies from dependency file
+// and it was compiled with the same command line.
+// Without dependency file (`-MD`, `-MF` or `/sourceDependencies`) task is never up to date.
+fn is_up_to_date(state: &SharedState, task: &CompilationTask) -> bool {
+    let Some(deps_file) = &task.shared.deps_file else {
+        return false;
+    };
+    if !fs::read_to_string(command_record(state, task)).is_ok_and(|hash| hash == command_hash(task))
+    {
+        return false;
+    }
+    let modified = |path: &Path| fs::metadata(path).and_then(|meta| meta.modified()).ok();
+    let Some(output_time) = modified(&task.output_object) else {
+        return false;
+    };
+    let Ok(deps) = fs::read_to_string(deps_file) else {
+        return false;
+    };
+    let deps = match task.shared.deps_format {
+        DepsFormat::Make => parse_prerequisites(&deps),
+        DepsFormat::Json => {
+            let Some(deps) = parse_source_dependencies(&deps) else {
+                return false;
+            };
+            deps
+        }
+    };
+    std::iter::once(task.input_source.clone())
+        .chain(deps)
+        .all(|path| {
+            task.shared
+                .command
+                .absolutize(&path)
+                .ok()
+                .and_then(|path| modified(&path))
+                .is_some_and(|time| time <= output_time)
+        })
+}
+
+// Record of command line, which produced task output.
+fn command_record(state: &SharedState, task: &CompilationTask) -> PathBuf {
+    let mut hasher = Sha256::new();
+    hasher.update(task.output_object.to_string_lossy().as_bytes());
+    state.command_records.join(hex::encode(hasher.finalize()))
+}
+
+fn command_hash(task: &CompilationTask) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(
+        format!(
+            "{} {} {:?}",
+            task.shared.command.program.display(),
+            task.language,
+            task.shared.args
+        )
+        .as_bytes(),
+    );
+    hex::encode(hasher.finalize())
+}
+
+fn save_command_hash(state: &SharedState, task: &CompilationTask) -> std::io::Result<()> {
+    fs::create_dir_all(&state.command_records)?;
+    fs::write(command_record(state, task), command_hash(task))
+}
+
 pub enum BuildAction {
     Empty,
     Exec(CommandInfo, CommandArgs),
@@ -57,12 +150,24 @@ pub struct BuildResult<'a> {
     pub worker: usize,
     // Build result
     pub result: &'a BuildTaskResult,
+    // Build progress after task completion
+    pub status: BuildStatus,
+}
+
+pub struct BuildStatus {
     // Completed task count
     pub completed: usize,
     // Total task count
     pub total: usize,
+    // Estimated time until all tasks are completed (based on task history)
+    pub estimate: Option<Duration>,
+    // Tasks still in progress
+    pub running: Vec<Arc<BuildTask>>,
 }
 
+// Build status refresh interval while no task completes.
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+
 struct ResultMessage {
     index: NodeIndex,
     task: Arc<BuildTask>,
@@ -75,18 +180,109 @@ struct TaskMessage {
     task: Arc<BuildTask>,
 }
 
-impl<'a> BuildResult<'a> {
-    fn new(message: &'a ResultMessage, completed: &mut usize, total: usize) -> Self {
-        *completed += 1;
+struct BuildProgress<'a> {
+    // Task durations for estimate (None - estimate is disabled)
+    history: Option<&'a TaskHistory>,
+    // Tasks in progress by worker number
+    running: &'a [Mutex<Option<Arc<BuildTask>>>],
+    // Task identities for history lookup
+    identities: Vec<Option<String>>,
+    // Expected task durations (None - no task history at all)
+    expected: Option<Vec<Duration>>,
+    // Expected duration of not completed tasks
+    remaining: Duration,
+    workers: u32,
+    completed: usize,
+    // Last task completion time
+    updated: Instant,
+}
+
+impl<'a> BuildProgress<'a> {
+    fn new(
+        history: Option<&'a TaskHistory>,
+        graph: &BuildGraph,
+        running: &'a [Mutex<Option<Arc<BuildTask>>>],
+    ) -> Self {
+        let identities: Vec<Option<String>> = graph
+            .node_weights()
+            .map(|task| task.action.identity())
+            .collect();
+        let known: Vec<Duration> = identities
+            .iter()
+            .flatten()
+            .filter_map(|identity| history?.get(identity))
+            .collect();
+        // Tasks without history are expected to take as long as an average known task
+        let expected = u32::try_from(known.len())
+            .ok()
+            .filter(|count| *count > 0)
+            .map(|count| known.iter().sum::<Duration>() / count)
+            .map(|average| -> Vec<Duration> {
+                identities
+                    .iter()
+                    .map(|identity| match identity {
+                        Some(identity) => history
+                            .and_then(|history| history.get(identity))
+                            .unwrap_or(average),
+                        None => Duration::ZERO,
+                    })
+                    .collect()
+            });
+        BuildProgress {
+            history,
+            running,
+            identities,
+            remaining: expected.iter().flatten().sum(),
+            expected,
+            workers: u32::try_from(running.len()).unwrap_or(u32::MAX),
+            completed: 0,
+            updated: Instant::now(),
+        }
+    }
+
+    fn status(&self, total: usize) -> BuildStatus {
+        BuildStatus {
+            completed: self.completed,
+            total,
+            // Running tasks keep going between completions
+            estimate: self.expected.as_ref().map(|_| {
+                (self.remaining / max(self.workers, 1)).saturating_sub(self.updated.elapsed())
+            }),
+            running: self
+                .running
+                .iter()
+                .filter_map(|slot| slot.lock().unwrap().clone())
+                .collect(),
+        }
+    }
+
+    fn complete<'m>(&mut self, message: &'m ResultMessage, total: usize) -> BuildResult<'m> {
+        let index = message.index.index();
+        if let Some(expected) = &self.expected {
+            self.remaining = self.remaining.saturating_sub(expected[index]);
+        }
+        if let (Some(history), Some(identity), Ok(output)) = (
+            self.history,
+            &self.identities[index],
+            &message.result.output,
+        ) {
+            // Skipped task duration says nothing about compilation time
+            if output.success() && !message.result.skipped {
+                history.update(identity.clone(), message.result.duration);
+            }
+        }
+        self.completed += 1;
+        self.updated = Instant::now();
         BuildResult {
             worker: message.worker,
             task: &message.task,
             result: &message.result,
-            completed: *completed,
-            total,
+            status: self.status(total),
         }
     }
+}
 
+impl BuildResult<'_> {
     pub fn print(self) -> crate::Result<()> {
         if let Ok(ref output) = self.result.output {
             std::io::stdout().write_all(&output.stdout)?;
@@ -127,6 +323,36 @@ impl BuildAction {
         actions
     }
 
+    // Stable task identity for task history.
+    #[must_use]
+    pub fn identity(&self) -> Option<String> {
+        match &self {
+            BuildAction::Empty => None,
+            BuildAction::Exec(command, args) => {
+                Some(format!("{} {args:?}", command.program.display()))
+            }
+            BuildAction::Compilation(toolchain, task) => {
+                let toolchain = toolchain
+                    .identifier()
+                    .unwrap_or_else(|| task.shared.command.program.display().to_string());
+                Some(format!(
+                    "{toolchain} {} {}",
+                    task.input_source.display(),
+                    task.output_object.display()
+                ))
+            }
+        }
+    }
+
+    // Output object of compilation task.
+    #[must_use]
+    pub fn output(&self) -> Option<&Path> {
+        match &self {
+            BuildAction::Compilation(_, task) => Some(&task.output_object),
+            _ => None,
+        }
+    }
+
     #[must_use]
     pub fn title(&self) -> Cow<'_, str> {
         match &self {
@@ -168,15 +394,17 @@ pub fn validate_graph<N, E>(graph: Graph<N, E>) -> crate::Result<Graph<N, E>> {
     Err(crate::Error::CyclesInBuildGraph)
 }
 
-fn execute_until_failed<F>(
+fn execute_until_failed<F, S>(
     graph: &BuildGraph,
     tx_task: &crossbeam_channel::Sender<TaskMessage>,
     rx_result: &crossbeam_channel::Receiver<ResultMessage>,
-    count: &mut usize,
+    progress: &mut BuildProgress,
     update_progress: F,
+    update_status: S,
 ) -> crate::Result<()>
 where
     F: Fn(&BuildResult) -> crate::Result<()>,
+    S: Fn(&BuildStatus) -> crate::Result<()>,
 {
     let mut completed: Vec<bool> = vec![false; graph.node_count()];
     for index in graph.externals(EdgeDirection::Outgoing) {
@@ -186,10 +414,19 @@ where
         })?;
     }
 
-    for message in rx_result {
+    loop {
+        // Refresh running tasks and estimate during long tasks
+        let message = match rx_result.recv_timeout(STATUS_INTERVAL) {
+            Ok(message) => message,
+            Err(crossbeam_channel::RecvTimeoutError::Timeout) => {
+                update_status(&progress.status(graph.node_count()))?;
+                continue;
+            }
+            Err(crossbeam_channel::RecvTimeoutError::Disconnected) => break,
+        };
         assert!(!completed[message.index.index()]);
 
-        update_progress(&BuildResult::new(&message, count, graph.node_count()))?;
+        update_progress(&progress.complete(&message, graph.node_count()))?;
         let output = message.result.output?;
         if !output.success() {
             return Err(crate::Error::from("Build failed"));
@@ -205,7 +442,7 @@ where
             }
         }
 
-        if *count == completed.len() {
+        if progress.completed == completed.len() {
             return Ok(());
         }
     }
@@ -223,14 +460,17 @@ fn is_ready<N, E>(graph: &Graph<N, E>, completed: &[bool], source: NodeIndex) ->
     true
 }
 
-pub fn execute_graph<F>(
+pub fn execute_graph<F, S>(
     state: &SharedState,
     build_graph: BuildGraph,
     process_limit: usize,
+    history: Option<&TaskHistory>,
     update_progress: F,
+    update_status: S,
 ) -> crate::Result<()>
 where
     F: Fn(&BuildResult) -> crate::Result<()>,
+    S: Fn(&BuildStatus) -> crate::Result<()>,
 {
     let graph = validate_graph(build_graph)?;
     if graph.node_count() == 0 {
@@ -240,16 +480,33 @@ where
     let (tx_result, rx_result) = crossbeam_channel::unbounded::<ResultMessage>();
     let (tx_task, rx_task) = crossbeam_channel::unbounded::<TaskMessage>();
     let num_cpus = max(1, min(process_limit, graph.node_count()));
+    let running: Vec<Mutex<Option<Arc<BuildTask>>>> =
+        (0..num_cpus).map(|_| Mutex::new(None)).collect();
     std::thread::scope(|scope| {
-        for worker_id in 0..num_cpus {
+        for (worker_id, local_running) in running.iter().enumerate() {
             let local_rx_task = rx_task.clone();
             let local_tx_result = tx_result.clone();
             scope.spawn(move || {
                 while let Ok(message) = local_rx_task.recv() {
+                    *local_running.lock().unwrap() = Some(message.task.clone());
+                    state.events.send(&Event::TaskStarted {
+                        worker: worker_id,
+                        title: &message.task.title,
+                        output: message.task.action.output(),
+                    });
+                    let result = message.task.execute(state);
+                    state.events.send(&Event::TaskFinished {
+                        worker: worker_id,
+                        title: &message.task.title,
+                        output: message.task.action.output(),
+                        success: result.success(),
+                        duration_ms: result.duration.as_millis(),
+                    });
+                    *local_running.lock().unwrap() = None;
                     match local_tx_result.send(ResultMessage {
                         index: message.index,
                         worker: worker_id,
-                        result: message.task.execute(state),
+                        result,
                         task: message.task,
                     }) {
                         Ok(_) => {}
@@ -262,15 +519,21 @@ where
         }
         drop(tx_result);
         // Run all tasks.
-        let mut count: usize = 0;
-        let result =
-            execute_until_failed(&graph, &tx_task, &rx_result, &mut count, &update_progress);
+        let mut progress = BuildProgress::new(history, &graph, &running);
+        let result = execute_until_failed(
+            &graph,
+            &tx_task,
+            &rx_result,
+            &mut progress,
+            &update_progress,
+            &update_status,
+        );
         // Cleanup task queue.
         drop(tx_task);
         drop(rx_task);
         // Wait for in progress task completion.
         for message in rx_result {
-            update_progress(&BuildResult::new(&message, &mut count, graph.node_count()))?;
+            update_progress(&progress.complete(&message, graph.node_count()))?;
         }
         result
     })
@@ -278,19 +541,55 @@ where
 
 #[cfg(test)]
 mod test {
+    use std::fs;
+    use std::fs::File;
+    use std::path::{Path, PathBuf};
     use std::sync::{Arc, Mutex};
+    use std::time::{Duration, SystemTime};
 
-    use crate::compiler::SharedState;
+    use crate::compiler::{BuildTaskResult, CommandArgs, CommandInfo, OutputInfo, SharedState};
     use crate::config::Config;
-    use crate::worker::{execute_graph, BuildAction, BuildGraph, BuildTask};
+    use crate::io::history::TaskHistory;
+    use crate::worker::{
+        execute_graph, is_up_to_date, save_command_hash, BuildAction, BuildGraph, BuildProgress,
+        BuildTask, ResultMessage,
+    };
+
+    fn state(cache: &Path) -> SharedState {
+        SharedState::new(&Config {
+            cache: cache.join("cache"),
+            ..Config::default()
+        })
+        .unwrap()
+    }
+
+    // Write file with modification time in the past.
+    fn write_aged(path: &Path, data: &str, age_secs: u64) {
+        fs::write(path, data).unwrap();
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(age_secs))
+            .unwrap();
+    }
 
     #[test]
     fn test_execute_graph_empty() {
         let state = SharedState::new(&Config::default()).unwrap();
         let graph = BuildGraph::new();
-        execute_graph(&state, graph, 2, |_| {
-            unreachable!();
-        })
+        execute_graph(
+            &state,
+            graph,
+            2,
+            None,
+            |_| {
+                unreachable!();
+            },
+            |_| {
+                unreachable!();
+            },
+        )
         .unwrap();
     }
 
@@ -306,10 +605,17 @@ mod test {
         }));
 
         let result = Mutex::new(Vec::new());
-        execute_graph(&state, graph, 4, |r| {
-            result.lock().unwrap().push(r.task.title.clone());
-            Ok(())
-        })
+        execute_graph(
+            &state,
+            graph,
+            4,
+            None,
+            |r| {
+                result.lock().unwrap().push(r.task.title.clone());
+                Ok(())
+            },
+            |_| Ok(()),
+        )
         .unwrap();
 
         let actual: Vec<String> = result.lock().unwrap().clone();
@@ -334,13 +640,201 @@ mod test {
         graph.add_edge(t2, t1, ());
 
         let result = Mutex::new(Vec::new());
-        execute_graph(&state, graph, 4, |r| {
-            result.lock().unwrap().push(r.task.title.clone());
-            Ok(())
-        })
+        execute_graph(
+            &state,
+            graph,
+            4,
+            None,
+            |r| {
+                result.lock().unwrap().push(r.task.title.clone());
+                Ok(())
+            },
+            |_| Ok(()),
+        )
         .unwrap();
 
         let actual: Vec<String> = result.lock().unwrap().clone();
         assert_eq!(actual, vec!["task 1".to_string(), "task 2".to_string()]);
     }
+
+    // Status of long task is reported before its completion
+    #[cfg(unix)]
+    #[test]
+    fn test_execute_graph_status() {
+        let state = SharedState::new(&Config::default()).unwrap();
+        let mut graph = BuildGraph::new();
+        graph.add_node(Arc::new(BuildTask {
+            title: "sleep".to_string(),
+            action: BuildAction::Exec(
+                CommandInfo::simple(PathBuf::from("sleep")),
+                CommandArgs::Vec(vec!["3".to_string()]),
+            ),
+        }));
+
+        let running = Mutex::new(Vec::new());
+        execute_graph(
+            &state,
+            graph,
+            4,
+            None,
+            |r| {
+                assert_eq!(r.status.completed, 1);
+                assert!(r.status.running.is_empty());
+                Ok(())
+            },
+            |s| {
+                assert_eq!(s.completed, 0);
+                assert_eq!(s.total, 1);
+                let mut running = running.lock().unwrap();
+                running.extend(s.running.iter().map(|task| task.title.clone()));
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        assert!(running.lock().unwrap().contains(&"sleep".to_string()));
+    }
+
+    #[test]
+    fn test_skipped_task_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = TaskHistory::load(&dir.path().join("history"));
+        let mut graph = BuildGraph::new();
+        let task = Arc::new(BuildTask {
+            title: "task".to_string(),
+            action: BuildAction::Exec(
+                CommandInfo::simple(PathBuf::from("cc")),
+                CommandArgs::Vec(vec!["a.c".to_string()]),
+            ),
+        });
+        let index = graph.add_node(task.clone());
+        let identity = task.action.identity().unwrap();
+        let running = [Mutex::new(None)];
+        let mut progress = BuildProgress::new(Some(&history), &graph, &running);
+        let message = |skipped: bool| ResultMessage {
+            index,
+            task: task.clone(),
+            worker: 0,
+            result: BuildTaskResult {
+                output: Ok(OutputInfo {
+                    status: Some(0),
+                    stdout: Vec::new(),
+                    stderr: Vec::new(),
+                }),
+                duration: Duration::from_millis(1),
+                skipped,
+            },
+        };
+
+        // Up to date task doesn't overwrite duration of real compilation
+        progress.complete(&message(true), 1);
+        assert_eq!(history.get(&identity), None);
+        progress.complete(&message(false), 1);
+        assert_eq!(history.get(&identity), Some(Duration::from_millis(1)));
+    }
+
+    #[test]
+    fn test_up_to_date_make() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("src.cpp");
+        let header = dir.path().join("a.h");
+        let object = dir.path().join("src.o");
+        write_aged(&source, "", 20);
+        write_aged(&header, "", 20);
+        // `-MD` without `-MF` writes dependency file next to object
+        write_aged(
+            &object.with_extension("d"),
+            &format!(
+                "{}: {} {}\n",
+                object.display(),
+                source.display(),
+                header.display()
+            ),
+            10,
+        );
+        let state = state(dir.path());
+        let task = |extra: &[&str]| {
+            let mut args = vec![
+                "-c".to_string(),
+                "-MD".to_string(),
+                source.display().to_string(),
+                "-o".to_string(),
+                object.display().to_string(),
+            ];
+            args.extend(extra.iter().map(|arg| (*arg).to_string()));
+            crate::clang::prepare::create_tasks(
+                CommandInfo::simple(PathBuf::from("clang")),
+                args,
+                true,
+            )
+            .unwrap()
+            .remove(0)
+        };
+        let task_o2 = task(&["-O2"]);
+        let task = task(&[]);
+        assert_eq!(task.shared.deps_file, Some(object.with_extension("d")));
+
+        assert!(!is_up_to_date(&state, &task));
+        write_aged(&object, "", 10);
+        // Output without command line record may be built with other flags
+        assert!(!is_up_to_date(&state, &task));
+        save_command_hash(&state, &task).unwrap();
+        assert!(is_up_to_date(&state, &task));
+        assert!(!is_up_to_date(&state, &task_o2));
+        write_aged(&header, "", 0);
+        assert!(!is_up_to_date(&state, &task));
+    }
+
+    #[test]
+    fn test_up_to_date_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("src.cpp");
+        let header = dir.path().join("a.h");
+        let object = dir.path().join("src.obj");
+        let deps = dir.path().join("src.json");
+        write_aged(&source, "", 20);
+        write_aged(&header, "", 20);
+        write_aged(&object, "", 10);
+        write_aged(
+            &deps,
+            &serde_json::json!({
+                "Version": "1.2",
+                "Data": {
+                    "Source": source,
+                    "Includes": [header],
+                },
+            })
+            .to_string(),
+            10,
+        );
+        // Relative paths, as MSVC arguments can't start with `/`
+        let command = CommandInfo {
+            current_dir: Some(dir.path().to_path_buf()),
+            ..CommandInfo::simple(PathBuf::from("cl.exe"))
+        };
+        let task = crate::vs::prepare::create_tasks(
+            command,
+            [
+                "/c",
+                "src.cpp",
+                "/Fosrc.obj",
+                "/sourceDependencies",
+                "src.json",
+            ]
+            .map(str::to_string)
+            .to_vec(),
+            true,
+        )
+        .unwrap()
+        .remove(0);
+        let state = state(dir.path());
+        save_command_hash(&state, &task).unwrap();
+
+        assert!(is_up_to_date(&state, &task));
+        write_aged(&header, "", 0);
+        assert!(!is_up_to_date(&state, &task));
+        // Unparsable dependency file
+        write_aged(&deps, "src.obj: src.cpp", 10);
+        assert!(!is_up_to_date(&state, &task));
+    }
 }